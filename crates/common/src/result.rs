@@ -39,6 +39,31 @@ pub enum WasmErrorInner {
     ModuleDeserialize(String),
     /// The host failed to call a function in the guest.
     CallError(String),
+    /// Wasmer failed to instantiate a Module, e.g. because an import was
+    /// missing or the start function trapped.
+    Instantiate(String),
+    /// The guest does not export something the host requires, or exports it
+    /// with the wrong type. Holds the name of the export.
+    MissingExport(String),
+    /// The guest ran out of metering points and was halted.
+    OutOfPoints,
+    /// The guest exhausted its call stack.
+    StackOverflow,
+    /// The guest executed an `unreachable` instruction, e.g. because it
+    /// panicked.
+    Unreachable,
+    /// The guest accessed memory outside the bounds of its linear memory.
+    OutOfBoundsMemory,
+    /// The guest needed more memory than it is allowed to grow to.
+    MemoryLimit,
+    /// The guest was still running when the deadline for the call passed.
+    Timeout,
+    /// The backend running the guest has no metering, so metering points
+    /// can't be read or set.
+    MeteringUnsupported,
+    /// The guest doesn't check for interrupts, so it can't be called with a
+    /// deadline.
+    Uninterruptible,
 }
 
 impl WasmErrorInner {
@@ -59,6 +84,19 @@ impl WasmErrorInner {
             | Self::ModuleDeserialize(_)
             // This is ambiguous so best to treat as potentially corrupt.
             | Self::CallError(_)
+            // An instance that could not be built or wired up is unusable.
+            | Self::Instantiate(_)
+            | Self::MissingExport(_)
+            // A trap halts the guest part way through whatever it was doing,
+            // so allocations may be leaked and guest state left inconsistent.
+            // This holds even when the cause is external, as with metering.
+            | Self::OutOfPoints
+            | Self::StackOverflow
+            | Self::Unreachable
+            | Self::OutOfBoundsMemory
+            | Self::MemoryLimit
+            // The guest was either interrupted like a trap or is still running.
+            | Self::Timeout
              => true,
             // (De)serialization simply means some input/output data was
             // unrecognisable somehow, it doesn't corrupt the guest memory.
@@ -72,7 +110,10 @@ impl WasmErrorInner {
             // As long as `consume_bytes_from_guest` is used by the host it will
             // have already cleaned up all memory leaks in the guest before the
             // host can even run something that may fail and short circuit.
-            | Self::HostShortCircuit(_)=> false,
+            | Self::HostShortCircuit(_)
+            // Nothing was done to the guest.
+            | Self::MeteringUnsupported
+            | Self::Uninterruptible => false,
         }
     }
 }
@@ -6,8 +6,6 @@ use wasmer::Global;
 use wasmer::Memory;
 use wasmer::StoreMut;
 use wasmer::TypedFunction;
-#[cfg(feature = "wasmer-sys")]
-use wasmer_middlewares::metering::MeteringPoints;
 
 #[derive(Clone, Default)]
 pub struct Env {
@@ -103,86 +101,37 @@ impl Env {
         }
     }
 
-    #[cfg(feature = "wasmer-sys")]
-    /// Mimics upstream function of the same name but accesses the global directly from env.
-    /// https://github.com/wasmerio/wasmer/blob/master/lib/middlewares/src/metering.rs#L285
-    pub fn get_remaining_points(
-        &self,
-        store_mut: &mut StoreMut,
-    ) -> Result<MeteringPoints, wasmer::RuntimeError> {
-        let exhausted: i32 = self
-            .wasmer_metering_points_exhausted
-            .as_ref()
-            .ok_or(wasm_error!(WasmErrorInner::Memory))?
-            .get(store_mut)
-            .try_into()
-            .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
-
-        if exhausted > 0 {
-            return Ok(MeteringPoints::Exhausted);
-        }
-
-        let points = self
-            .wasmer_metering_remaining_points
-            .as_ref()
-            .ok_or(wasm_error!(WasmErrorInner::Memory))?
-            .get(store_mut)
-            .try_into()
-            .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
-
-        Ok(MeteringPoints::Remaining(points))
-    }
+    /// Run `f` with the [`Env`] and store of an async host function.
+    ///
+    /// Async host functions get an [`wasmer::AsyncFunctionEnvMut`] rather than
+    /// a [`wasmer::FunctionEnvMut`], and the store is only available while
+    /// it is locked. This locks it for the duration of `f` so that the usual
+    /// [`Env::consume_bytes_from_guest`] and [`Env::move_data_to_guest`] can be
+    /// used, e.g.
+    ///
+    /// ```ignore
+    /// let input: String = Env::with_async(&env, |env, store_mut| {
+    ///     env.consume_bytes_from_guest(store_mut, guest_ptr, len)
+    /// })
+    /// .await?;
+    /// let output = do_some_io(input).await;
+    /// Env::with_async(&env, |env, store_mut| {
+    ///     env.move_data_to_guest(store_mut, Ok::<String, WasmError>(output))
+    /// })
+    /// .await
+    /// ```
+    ///
+    /// Don't hold on to anything from inside `f` across an `.await`, the guest
+    /// can't resume until the store is unlocked again.
+    #[cfg(feature = "async")]
+    pub async fn with_async<R>(
+        env: &wasmer::AsyncFunctionEnvMut<Env>,
+        f: impl FnOnce(&Env, &mut StoreMut) -> R,
+    ) -> R {
+        use wasmer::AsStoreMut;
 
-    #[cfg(feature = "wasmer-sys")]
-    pub fn set_remaining_points(
-        &self,
-        store_mut: &mut StoreMut,
-        points: u64,
-    ) -> Result<(), wasmer::RuntimeError> {
-        self.wasmer_metering_remaining_points
-            .as_ref()
-            .ok_or(wasm_error!(WasmErrorInner::Memory))?
-            .set(store_mut, points.into())
-            .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
-
-        self.wasmer_metering_points_exhausted
-            .as_ref()
-            .ok_or(wasm_error!(WasmErrorInner::Memory))?
-            .set(store_mut, 0i32.into())
-            .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
-        Ok(())
-    }
-
-    #[cfg(feature = "wasmer-sys")]
-    pub fn decrease_points(
-        &self,
-        store_mut: &mut StoreMut,
-        points: u64,
-    ) -> Result<MeteringPoints, wasmer::RuntimeError> {
-        match self.get_remaining_points(store_mut) {
-            Ok(MeteringPoints::Remaining(remaining)) => {
-                if remaining < points {
-                    self.wasmer_metering_remaining_points
-                        .as_ref()
-                        .ok_or(wasm_error!(WasmErrorInner::Memory))?
-                        .set(store_mut, 0i32.into())
-                        .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
-                    self.wasmer_metering_points_exhausted
-                        .as_ref()
-                        .ok_or(wasm_error!(WasmErrorInner::Memory))?
-                        .set(store_mut, 1i32.into())
-                        .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
-                    Ok(MeteringPoints::Exhausted)
-                } else {
-                    self.wasmer_metering_remaining_points
-                        .as_ref()
-                        .ok_or(wasm_error!(WasmErrorInner::Memory))?
-                        .set(store_mut, (remaining - points).into())
-                        .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
-                    Ok(MeteringPoints::Remaining(remaining - points))
-                }
-            }
-            v => v,
-        }
+        let mut handle = env.write().await;
+        let (env, store) = handle.data_and_store_mut();
+        f(env, &mut store.as_store_mut())
     }
 }
@@ -1,13 +1,24 @@
+pub(crate) mod interrupt;
+
 use crate::prelude::*;
+use crate::workers::Workers;
 use core::num::TryFromIntError;
 use holochain_serialized_bytes::prelude::*;
+use interrupt::Interrupt;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::Instant;
+use wasmer::AsStoreMut;
 use wasmer::Instance;
 use wasmer::Memory;
 use wasmer::MemoryView;
+use wasmer::Store;
 use wasmer::StoreMut;
 use wasmer::Value;
 use wasmer::WasmSlice;
+use wasmer_types::TrapCode;
 
 /// Write a slice of bytes to the guest in a safe-ish way.
 ///
@@ -122,6 +133,53 @@ where
     }
 }
 
+/// Set the metering budget of an instance, typically right before [`call`].
+///
+/// The metering middleware only sets the initial points when an instance is
+/// created, and every call draws from the same pool after that. Resetting the
+/// points before each call gives every call its own budget, so different
+/// kinds of call (e.g. validation vs. user calls) can be given different
+/// limits without recompiling the module.
+///
+/// This also clears the exhausted flag, so an instance that ran out of points
+/// can be given a fresh budget.
+///
+/// Fails with [`WasmErrorInner::MissingExport`] if the module was neither
+/// compiled with the metering middleware nor instrumented with
+/// [`crate::module::inject_metering`], and with
+/// [`WasmErrorInner::MeteringUnsupported`] if the backend has no metering.
+pub fn set_remaining_points(
+    store_mut: &mut StoreMut,
+    instance: &Instance,
+    points: u64,
+) -> Result<(), wasmer::RuntimeError> {
+    if !crate::metering::backend_supports_metering(store_mut.engine()) {
+        return Err(wasm_error!(WasmErrorInner::MeteringUnsupported).into());
+    }
+    instance
+        .exports
+        .get_global("wasmer_metering_remaining_points")
+        .map_err(|_| {
+            wasm_error!(WasmErrorInner::MissingExport(
+                "wasmer_metering_remaining_points".to_string()
+            ))
+        })?
+        .set(store_mut, points.into())
+        .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
+
+    instance
+        .exports
+        .get_global("wasmer_metering_points_exhausted")
+        .map_err(|_| {
+            wasm_error!(WasmErrorInner::MissingExport(
+                "wasmer_metering_points_exhausted".to_string()
+            ))
+        })?
+        .set(store_mut, 0i32.into())
+        .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
+    Ok(())
+}
+
 /// Host calling guest for the function named `call` with the given `payload` in a vector of bytes
 /// result is either a vector of bytes from the guest found at the location of the returned guest
 /// allocation pointer or a `RuntimeError` built from a `WasmError`.
@@ -137,10 +195,295 @@ pub fn call<I, O>(
 where
     I: serde::Serialize + std::fmt::Debug,
     O: serde::de::DeserializeOwned + std::fmt::Debug,
+{
+    call_inner(store_mut, instance, f, input, &mut CallStats::default())
+}
+
+/// Measurements taken by [`call_with_stats`] over a single call.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallStats {
+    /// Metering points used by the call, or `None` if the instance is not
+    /// metered. Points taken by host functions via `Env::decrease_points`
+    /// count too.
+    pub points_consumed: Option<u64>,
+    /// Whether the call ran out of metering points. The call will have failed
+    /// in this case.
+    pub points_exhausted: bool,
+    /// Length of the serialized input copied from the host into the guest.
+    pub bytes_in: usize,
+    /// Length of the serialized output copied from the guest back to the host.
+    pub bytes_out: usize,
+    /// Wall-clock time spent in the call, including (de)serialization.
+    pub duration: std::time::Duration,
+}
+
+/// Same as [`call`] but also measures the call, e.g. for billing or profiling.
+///
+/// The stats are returned whether or not the call succeeds, so that failed
+/// calls, including calls that ran out of points, can be accounted for too.
+pub fn call_with_stats<I, O>(
+    store_mut: &mut StoreMut,
+    instance: Arc<Instance>,
+    f: &str,
+    input: I,
+) -> (Result<O, wasmer::RuntimeError>, CallStats)
+where
+    I: serde::Serialize + std::fmt::Debug,
+    O: serde::de::DeserializeOwned + std::fmt::Debug,
+{
+    let mut stats = CallStats::default();
+    let points_before = remaining_points(store_mut, &instance);
+    let start = std::time::Instant::now();
+
+    let result = call_inner(store_mut, instance.clone(), f, input, &mut stats);
+
+    stats.duration = start.elapsed();
+    if let (Some((points_before, _)), Some((points_after, exhausted))) =
+        (points_before, remaining_points(store_mut, &instance))
+    {
+        stats.points_consumed = Some(points_before.saturating_sub(points_after));
+        stats.points_exhausted = exhausted;
+    }
+
+    (result, stats)
+}
+
+/// Same as [`call`] but gives up once `deadline` passes, failing with
+/// [`WasmErrorInner::Timeout`].
+///
+/// Metering bounds the work a guest can do but not how long it takes, so this
+/// is the only way to stop e.g. a guest stuck in a loop from hanging the host.
+///
+/// The guest runs on a worker thread that holds the lock on `store` for the
+/// duration of the call, and is interrupted once the deadline passes. Only
+/// instances of modules built to be interrupted, e.g. by a
+/// [`crate::module::ModuleBuilder`] with
+/// [`crate::module::ModuleBuilder::interruptible`], can be called this way.
+/// Calls into any other instance fail up front with
+/// [`WasmErrorInner::Uninterruptible`].
+///
+/// A guest that is blocked in a host function only sees the interrupt once
+/// the host function returns. The call still fails with a timeout shortly
+/// after the deadline, but the worker thread keeps the store locked until the
+/// guest gets back from the host. Either way the instance and its store must
+/// be discarded after a timeout.
+pub fn call_with_deadline<I, O>(
+    store: Arc<Mutex<Store>>,
+    instance: Arc<Instance>,
+    f: &str,
+    input: I,
+    deadline: Instant,
+) -> Result<O, wasmer::RuntimeError>
+where
+    I: serde::Serialize + std::fmt::Debug + Send + 'static,
+    O: serde::de::DeserializeOwned + std::fmt::Debug + Send + 'static,
+{
+    let interrupt =
+        Interrupt::new(&instance).ok_or_else(|| wasm_error!(WasmErrorInner::Uninterruptible))?;
+    if Instant::now() >= deadline {
+        return Err(wasm_error!(WasmErrorInner::Timeout).into());
+    }
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    {
+        let interrupt = interrupt.clone();
+        let f = f.to_string();
+        deadline_workers()
+            .run(move || {
+                let result =
+                    interrupt.watch(|| call(&mut store.lock().as_store_mut(), instance, &f, input));
+                // The caller may have given up waiting already.
+                let _ = sender.send(result);
+            })
+            .map_err(|e| wasm_error!(WasmErrorInner::CallError(e.to_string())))?;
+    }
+
+    match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(result) => return result,
+        Err(RecvTimeoutError::Timeout) => {}
+        Err(RecvTimeoutError::Disconnected) => return Err(call_panicked()),
+    }
+    // The guest may have returned by itself just as the deadline passed, in
+    // which case whatever it returned stands.
+    if let Ok(result) = receiver.try_recv() {
+        return result;
+    }
+
+    interrupt.fire();
+    match receiver.recv_timeout(INTERRUPT_GRACE_PERIOD) {
+        Ok(Err(_)) if interrupt.stopped() => Err(wasm_error!(WasmErrorInner::Timeout).into()),
+        // The guest finished, or failed by itself, before its next check.
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(wasm_error!(WasmErrorInner::Timeout).into()),
+        Err(RecvTimeoutError::Disconnected) => Err(call_panicked()),
+    }
+}
+
+/// Threads that run guest calls for [`call_with_deadline`].
+///
+/// A guest that is blocked in a host function keeps its thread past the
+/// deadline, so there is no limit on the number of threads, otherwise blocked
+/// guests could hold up calls that would finish in time.
+fn deadline_workers() -> &'static Workers {
+    static WORKERS: OnceLock<Workers> = OnceLock::new();
+    WORKERS.get_or_init(|| Workers::new("wasm-deadline-call", usize::MAX))
+}
+
+/// How long after the deadline to keep interrupting a guest before giving up
+/// on it, e.g. because it is blocked in a host function.
+const INTERRUPT_GRACE_PERIOD: Duration = Duration::from_millis(100);
+
+fn call_panicked() -> wasmer::RuntimeError {
+    wasm_error!(WasmErrorInner::CallError(
+        "guest call panicked on its thread".to_string()
+    ))
+    .into()
+}
+
+/// Remaining metering points of an instance and whether they are exhausted,
+/// or `None` if the instance is not metered. Exhausted points count as zero.
+pub(crate) fn remaining_points(
+    store_mut: &mut StoreMut,
+    instance: &Instance,
+) -> Option<(u64, bool)> {
+    let exhausted = instance
+        .exports
+        .get_global("wasmer_metering_points_exhausted")
+        .ok()?
+        .get(store_mut)
+        .i32()?
+        > 0;
+    if exhausted {
+        return Some((0, true));
+    }
+    let remaining = instance
+        .exports
+        .get_global("wasmer_metering_remaining_points")
+        .ok()?
+        .get(store_mut)
+        .i64()?;
+    Some((remaining as u64, false))
+}
+
+/// Classify an error raised while running guest code.
+///
+/// Traps that callers commonly need to react to get their own variant, and
+/// everything else is reported as [`WasmErrorInner::CallError`]. Running out
+/// of metering points traps as `unreachable`, so the metering globals are
+/// checked first to tell the two apart. Guests abort with `unreachable` when
+/// they can't allocate either, so that is reported as
+/// [`WasmErrorInner::MemoryLimit`] if the memory failed to grow past its limit
+/// since `memory_limit_hits` were counted at the start of the call.
+fn trap_error(
+    store_mut: &mut StoreMut,
+    instance: &Instance,
+    memory_limit_hits: u64,
+    e: wasmer::RuntimeError,
+) -> WasmErrorInner {
+    if let Some((_, true)) = remaining_points(store_mut, instance) {
+        return WasmErrorInner::OutOfPoints;
+    }
+    match e.clone().to_trap() {
+        Some(TrapCode::StackOverflow) => WasmErrorInner::StackOverflow,
+        Some(TrapCode::UnreachableCodeReached)
+            if crate::module::memory_limit_hits(store_mut, instance) > memory_limit_hits =>
+        {
+            WasmErrorInner::MemoryLimit
+        }
+        Some(TrapCode::UnreachableCodeReached) => WasmErrorInner::Unreachable,
+        Some(TrapCode::HeapAccessOutOfBounds) => WasmErrorInner::OutOfBoundsMemory,
+        _ => WasmErrorInner::CallError(e.to_string()),
+    }
+}
+
+fn call_inner<I, O>(
+    store_mut: &mut StoreMut,
+    instance: Arc<Instance>,
+    f: &str,
+    input: I,
+    stats: &mut CallStats,
+) -> Result<O, wasmer::RuntimeError>
+where
+    I: serde::Serialize + std::fmt::Debug,
+    O: serde::de::DeserializeOwned + std::fmt::Debug,
+{
+    let function = instance
+        .exports
+        .get_function(f)
+        .map_err(|e| wasm_error!(WasmErrorInner::CallError(e.to_string())))?;
+    let memory_limit_hits = crate::module::memory_limit_hits(store_mut, &instance);
+    let params = call_input(store_mut, &instance, memory_limit_hits, input, stats)?;
+    let result = function.call(store_mut, &params);
+    call_output(store_mut, &instance, memory_limit_hits, result, stats)
+}
+
+/// Host calling guest asynchronously, otherwise the same as [`call`].
+///
+/// The store is only locked while the input and output are moved in and out
+/// of the guest. While the guest runs it may suspend on async host functions,
+/// e.g. to wait on IO, and let the runtime get on with other work meanwhile,
+/// see [`Env::with_async`].
+///
+/// Only the sys backend supports async calls, on other backends this fails
+/// with a [`WasmErrorInner::CallError`].
+#[cfg(feature = "async")]
+pub async fn call_async<I, O>(
+    store: &wasmer::StoreAsync,
+    instance: Arc<Instance>,
+    f: &str,
+    input: I,
+) -> Result<O, wasmer::RuntimeError>
+where
+    I: serde::Serialize + std::fmt::Debug,
+    O: serde::de::DeserializeOwned + std::fmt::Debug,
+{
+    use wasmer::AsStoreAsync;
+
+    let mut stats = CallStats::default();
+    let function = instance
+        .exports
+        .get_function(f)
+        .map_err(|e| wasm_error!(WasmErrorInner::CallError(e.to_string())))?
+        .clone();
+    let (memory_limit_hits, params) = {
+        let mut store_lock = store.write_lock().await;
+        let mut store_mut = store_lock.as_store_mut();
+        let memory_limit_hits = crate::module::memory_limit_hits(&mut store_mut, &instance);
+        let params = call_input(
+            &mut store_mut,
+            &instance,
+            memory_limit_hits,
+            input,
+            &mut stats,
+        )?;
+        (memory_limit_hits, params)
+    };
+    let result = function.call_async(store, params.to_vec()).await;
+    call_output(
+        &mut store.write_lock().await.as_store_mut(),
+        &instance,
+        memory_limit_hits,
+        result,
+        &mut stats,
+    )
+}
+
+/// Move the input of a call into the guest and return the parameters to call
+/// the guest function with.
+fn call_input<I>(
+    store_mut: &mut StoreMut,
+    instance: &Instance,
+    memory_limit_hits: u64,
+    input: I,
+    stats: &mut CallStats,
+) -> Result<[Value; 2], wasmer::RuntimeError>
+where
+    I: serde::Serialize + std::fmt::Debug,
 {
     // The guest will use the same crate for decoding if it uses the wasm common crate.
     let payload: Vec<u8> =
         holochain_serialized_bytes::encode(&input).map_err(|e| wasm_error!(e))?;
+    stats.bytes_in = payload.len();
 
     // Get a pre-allocated guest pointer to write the input into.
     let guest_input_length = payload
@@ -154,7 +497,7 @@ where
         .get_function("__hc__allocate_1")
         .map_err(|e| wasm_error!(WasmErrorInner::CallError(e.to_string())))?
         .call(store_mut, std::slice::from_ref(&guest_input_length_value))
-        .map_err(|e| wasm_error!(WasmErrorInner::CallError(e.to_string())))?
+        .map_err(|e| wasm_error!(trap_error(store_mut, instance, memory_limit_hits, e)))?
         .first()
     {
         Some(Value::I32(guest_input_ptr)) => (
@@ -184,16 +527,23 @@ where
         &payload,
     )?;
 
-    // Call the guest function with its own pointer to its input.
+    Ok([guest_input_ptr_value, guest_input_length_value])
+}
+
+/// Move the output of a call out of the guest, given the result of calling
+/// the guest function.
+fn call_output<O>(
+    store_mut: &mut StoreMut,
+    instance: &Instance,
+    memory_limit_hits: u64,
+    result: Result<Box<[Value]>, wasmer::RuntimeError>,
+    stats: &mut CallStats,
+) -> Result<O, wasmer::RuntimeError>
+where
+    O: serde::de::DeserializeOwned + std::fmt::Debug,
+{
     // Collect the guest's pointer to its output.
-    let (guest_return_ptr, len): (GuestPtr, Len) = match instance
-        .exports
-        .get_function(f)
-        .map_err(|e| wasm_error!(WasmErrorInner::CallError(e.to_string())))?
-        .call(
-            store_mut,
-            &[guest_input_ptr_value, guest_input_length_value],
-        ) {
+    let (guest_return_ptr, len): (GuestPtr, Len) = match result {
         Ok(v) => match v.first() {
             Some(Value::I64(i)) => {
                 let u: GuestPtrLen = (*i)
@@ -210,6 +560,7 @@ where
                 error,
             }) => match error {
                 WasmErrorInner::HostShortCircuit(encoded) => {
+                    stats.bytes_out = encoded.len();
                     return match holochain_serialized_bytes::decode(&encoded) {
                         Ok(v) => Ok(v),
                         Err(e) => {
@@ -221,7 +572,7 @@ where
                             );
                             Err(wasm_error!(e).into())
                         }
-                    }
+                    };
                 }
                 _ => {
                     return Err(WasmHostError(WasmError {
@@ -232,10 +583,16 @@ where
                     .into())
                 }
             },
-            Err(e) => return Err(wasm_error!(WasmErrorInner::CallError(e.to_string())).into()),
+            Err(e) => {
+                return Err(
+                    wasm_error!(trap_error(store_mut, instance, memory_limit_hits, e)).into(),
+                )
+            }
         },
     };
 
+    stats.bytes_out = len as usize;
+
     // We ? here to return early WITHOUT calling deallocate.
     // The host MUST discard any wasm instance that errors at this point to avoid memory leaks.
     // The WasmError in the result type here is for deserializing out of the guest.
@@ -268,7 +625,190 @@ where
                 ),
             ],
         )
-        .map_err(|e| wasm_error!(WasmErrorInner::CallError(format!("{:?}", e))))?;
+        .map_err(|e| wasm_error!(trap_error(store_mut, instance, memory_limit_hits, e)))?;
 
     return_value.map_err(|e| WasmHostError(e).into())
 }
+
+#[cfg(all(test, feature = "wasmer-sys-cranelift"))]
+mod tests {
+    use crate::module::sys;
+    use crate::module::InstanceBuilder;
+    use crate::module::InstanceWithStore;
+    use crate::module::ModuleBuilder;
+    use crate::module::ModuleCache;
+    use crate::prelude::*;
+    use std::sync::LazyLock;
+    use std::time::Duration;
+    use std::time::Instant;
+    use wasmer::AsStoreMut;
+
+    const WAT: &[u8] = br#"(module
+        (import "env" "block" (func $block))
+        (memory (export "memory") 1)
+        (func (export "__hc__allocate_1") (param i32) (result i32) i32.const 0)
+        (func (export "__hc__deallocate_1") (param i32 i32))
+        (func (export "block") (param i32 i32) (result i64)
+            call $block
+            i64.const 0)
+        (func (export "loop_forever") (param i32 i32) (result i64)
+            (loop $continue br $continue)
+            i64.const 0)
+        (func (export "grow_and_abort") (param i32 i32) (result i64)
+            (drop (memory.grow (i32.const 1)))
+            unreachable)
+        (func (export "abort") (param i32 i32) (result i64)
+            unreachable))"#;
+
+    // Modules must not outlive their cache.
+    static METERED: LazyLock<ModuleCache> = LazyLock::new(|| cache(sys::make_engine, true));
+    static UNMETERED: LazyLock<ModuleCache> =
+        LazyLock::new(|| cache(wasmer::Engine::default, true));
+    static UNINTERRUPTIBLE: LazyLock<ModuleCache> =
+        LazyLock::new(|| cache(sys::make_engine, false));
+
+    fn cache(make_engine: fn() -> wasmer::Engine, interruptible: bool) -> ModuleCache {
+        ModuleCache::new_with_builder(
+            ModuleBuilder::new(make_engine, sys::make_runtime_engine).interruptible(interruptible),
+            None,
+        )
+    }
+
+    fn instance(metered: bool) -> InstanceWithStore {
+        instance_with_engine(metered, sys::make_runtime_engine)
+    }
+
+    fn instance_with_engine(
+        metered: bool,
+        make_engine: fn() -> wasmer::Engine,
+    ) -> InstanceWithStore {
+        let cache = if metered { &METERED } else { &UNMETERED };
+        instance_from_cache(cache, metered, make_engine)
+    }
+
+    fn instance_from_cache(
+        cache: &ModuleCache,
+        metered: bool,
+        make_engine: fn() -> wasmer::Engine,
+    ) -> InstanceWithStore {
+        let module = cache.get([0; 32], &wasmer::wat2wasm(WAT).unwrap()).unwrap();
+        InstanceBuilder::new(module, make_engine)
+            .metered(metered)
+            .build(|store_mut, _| {
+                wasmer::imports! {
+                    "env" => {
+                        "block" => wasmer::Function::new_typed(store_mut, || {
+                            std::thread::sleep(Duration::from_secs(2))
+                        }),
+                    }
+                }
+            })
+            .unwrap()
+    }
+
+    fn error<O: std::fmt::Debug>(result: Result<O, wasmer::RuntimeError>) -> WasmErrorInner {
+        result.unwrap_err().downcast::<WasmError>().unwrap().error
+    }
+
+    #[test]
+    fn call_with_deadline_blocked_in_host() {
+        let InstanceWithStore { store, instance } = instance(true);
+        let start = Instant::now();
+        let result = super::call_with_deadline::<_, ()>(
+            store,
+            instance,
+            "block",
+            (),
+            start + Duration::from_millis(50),
+        );
+        assert_eq!(WasmErrorInner::Timeout, error(result));
+        // The guest is still blocked, but the caller isn't.
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn call_with_deadline_out_of_points() {
+        let InstanceWithStore { store, instance } = instance(true);
+        super::set_remaining_points(&mut store.lock().as_store_mut(), &instance, 1_000).unwrap();
+        let result = super::call_with_deadline::<_, ()>(
+            store,
+            instance,
+            "loop_forever",
+            (),
+            Instant::now() + Duration::from_secs(60),
+        );
+        assert_eq!(WasmErrorInner::OutOfPoints, error(result));
+    }
+
+    #[test]
+    fn call_with_deadline_loop_forever() {
+        for metered in [true, false] {
+            let InstanceWithStore { store, instance } = instance(metered);
+            if metered {
+                super::set_remaining_points(&mut store.lock().as_store_mut(), &instance, u64::MAX)
+                    .unwrap();
+            }
+            let start = Instant::now();
+            let result = super::call_with_deadline::<_, ()>(
+                store.clone(),
+                instance,
+                "loop_forever",
+                (),
+                start + Duration::from_millis(50),
+            );
+            assert_eq!(WasmErrorInner::Timeout, error(result));
+            // The guest stopped rather than being left behind.
+            assert!(store.try_lock().is_some());
+            assert!(start.elapsed() < Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn call_with_deadline_ok() {
+        let InstanceWithStore { store, instance } = instance(false);
+        let result = super::call_with_deadline::<_, ()>(
+            store,
+            instance,
+            "abort",
+            (),
+            Instant::now() + Duration::from_secs(60),
+        );
+        // Failing by itself is not a timeout.
+        assert_eq!(WasmErrorInner::Unreachable, error(result));
+    }
+
+    #[test]
+    fn call_with_deadline_uninterruptible() {
+        let InstanceWithStore { store, instance } =
+            instance_from_cache(&UNINTERRUPTIBLE, true, sys::make_runtime_engine);
+        let result = super::call_with_deadline::<_, ()>(
+            store.clone(),
+            instance,
+            "loop_forever",
+            (),
+            Instant::now() + Duration::from_secs(60),
+        );
+        assert_eq!(WasmErrorInner::Uninterruptible, error(result));
+        assert!(store.try_lock().is_some());
+    }
+
+    #[test]
+    fn call_memory_limit() {
+        // The memory starts out as big as it can get.
+        let InstanceWithStore { store, instance } =
+            instance_with_engine(true, || sys::make_runtime_engine_with_memory_limit(1));
+        let call = |f| {
+            error(super::call::<_, ()>(
+                &mut store.lock().as_store_mut(),
+                instance.clone(),
+                f,
+                (),
+            ))
+        };
+
+        assert_eq!(WasmErrorInner::Unreachable, call("abort"));
+        assert_eq!(WasmErrorInner::MemoryLimit, call("grow_and_abort"));
+        // Only the call that ran into the limit is reported as such.
+        assert_eq!(WasmErrorInner::Unreachable, call("abort"));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::module::is_interruptible;
+use crate::prelude::*;
+use std::cell::RefCell;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use wasmer::Instance;
+
+/// Function calls and loop iterations an interruptible guest gets through
+/// between checks. Small enough that a guest stops within microseconds of
+/// being interrupted, large enough that checking costs next to nothing.
+const CHECK_INTERVAL: i32 = 10_000;
+
+thread_local! {
+    // The interrupt for guests called on this thread, see `Interrupt::watch`.
+    static WATCHED: RefCell<Option<Arc<State>>> = const { RefCell::new(None) };
+}
+
+#[derive(Default)]
+struct State {
+    fired: AtomicBool,
+    // Whether a guest has trapped since the interrupt fired.
+    stopped: AtomicBool,
+}
+
+/// Halts a guest that is running on another thread.
+///
+/// The guest's state belongs to the thread running it, so nothing is written
+/// to the guest from here. Instead the guest asks the host every so often
+/// whether to stop, through the check that [`crate::module::inject_interrupt`]
+/// adds to it, and the check traps once the interrupt has fired.
+#[derive(Clone)]
+pub(crate) struct Interrupt(Arc<State>);
+
+impl Interrupt {
+    /// `None` if the instance doesn't check for interrupts.
+    pub(crate) fn new(instance: &Instance) -> Option<Self> {
+        is_interruptible(instance.module()).then(|| Self(Arc::default()))
+    }
+
+    /// Run `f`, during which guests called on this thread stop at their next
+    /// check once the interrupt fires.
+    pub(crate) fn watch<R>(&self, f: impl FnOnce() -> R) -> R {
+        struct Unwatch(Option<Arc<State>>);
+        impl Drop for Unwatch {
+            fn drop(&mut self) {
+                WATCHED.with(|watched| *watched.borrow_mut() = self.0.take());
+            }
+        }
+
+        let _unwatch = Unwatch(WATCHED.with(|watched| watched.replace(Some(self.0.clone()))));
+        f()
+    }
+
+    pub(crate) fn fire(&self) {
+        self.0.fired.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether a guest was stopped by the interrupt, as opposed to failing
+    /// by itself.
+    pub(crate) fn stopped(&self) -> bool {
+        self.0.stopped.load(Ordering::Relaxed)
+    }
+}
+
+/// The host function behind the check import of interruptible guests.
+///
+/// Traps with [`WasmErrorInner::Timeout`] if the interrupt watched by this
+/// thread has fired, otherwise returns how long until the next check.
+pub(crate) fn check() -> Result<i32, wasmer::RuntimeError> {
+    WATCHED.with(|watched| match watched.borrow().as_ref() {
+        Some(state) if state.fired.load(Ordering::Relaxed) => {
+            state.stopped.store(true, Ordering::Relaxed);
+            Err(wasm_error!(WasmErrorInner::Timeout).into())
+        }
+        _ => Ok(CHECK_INTERVAL),
+    })
+}
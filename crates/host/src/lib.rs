@@ -44,6 +44,7 @@
 pub mod env;
 pub mod error;
 pub mod guest;
+pub mod metering;
 pub mod module;
 /// Adapted from: https://raw.githubusercontent.com/ticki/plru/master/src/lib.rs
 /// Updated for latest stable rust. Vendored largely as-is, so several
@@ -53,6 +54,7 @@ pub mod module;
 #[allow(dead_code)]
 pub(crate) mod plru;
 pub mod prelude;
+pub(crate) mod workers;
 
 // At least one wasmer backend must be enabled. The two backends (`wasmer-sys`
 // and `wasmer-wasmi`) are independent and can be enabled simultaneously; the
@@ -0,0 +1,281 @@
+use crate::prelude::*;
+use wasmer::Engine;
+use wasmer::Global;
+use wasmer::Module;
+use wasmer::Mutability;
+use wasmer::StoreMut;
+use wasmer::Type;
+
+/// The metering points left to a guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MeteringPoints {
+    /// The given number of metering points is left for the execution.
+    /// If the value is 0, all points are consumed but the execution was not terminated.
+    Remaining(u64),
+
+    /// The execution was terminated because the metering points were exhausted.
+    /// You can recover from this state by setting the points via
+    /// [`Metering::set_remaining_points`] and restart the execution.
+    Exhausted,
+}
+
+/// Read and change the metering points of a guest, whichever backend runs it.
+///
+/// Every method fails with [`WasmErrorInner::MeteringUnsupported`] if the
+/// backend running the guest has no metering, see
+/// [`backend_supports_metering`], and with [`WasmErrorInner::MissingExport`]
+/// if the guest isn't metered, see [`is_metered`].
+pub trait Metering {
+    /// Mimics upstream function of the same name.
+    /// https://github.com/wasmerio/wasmer/blob/master/lib/middlewares/src/metering.rs#L285
+    fn get_remaining_points(
+        &self,
+        store_mut: &mut StoreMut,
+    ) -> Result<MeteringPoints, wasmer::RuntimeError>;
+
+    /// Set the remaining points and clear the exhausted flag.
+    fn set_remaining_points(
+        &self,
+        store_mut: &mut StoreMut,
+        points: u64,
+    ) -> Result<(), wasmer::RuntimeError>;
+
+    /// Take `points` from the remaining points, exhausting them if there are
+    /// not enough left.
+    fn decrease_points(
+        &self,
+        store_mut: &mut StoreMut,
+        points: u64,
+    ) -> Result<MeteringPoints, wasmer::RuntimeError>;
+}
+
+/// Whether the backend of `engine` can meter guests at all.
+///
+/// The sys backend meters with the metering middleware and the wasmi backend
+/// with [`crate::module::inject_metering`]. Both keep the points in the same
+/// pair of exported globals, which is what [`Metering`] reads and writes.
+///
+/// This says nothing about any particular guest: only modules compiled with
+/// the metering middleware or instrumented with `inject_metering` are
+/// metered, see [`is_metered`].
+pub fn backend_supports_metering(engine: &Engine) -> bool {
+    #[cfg(feature = "wasmer-sys")]
+    if engine.is_sys() {
+        return true;
+    }
+    #[cfg(feature = "wasmer-wasmi")]
+    if engine.is_wasmi() {
+        return true;
+    }
+    false
+}
+
+/// Whether `module` exports the metering globals, i.e. was compiled with the
+/// metering middleware or instrumented with
+/// [`crate::module::inject_metering`].
+pub fn is_metered(module: &Module) -> bool {
+    let exports_global = |name: &str, ty: Type| {
+        module.exports().globals().any(|export| {
+            export.name() == name
+                && export.ty().ty == ty
+                && export.ty().mutability == Mutability::Var
+        })
+    };
+    exports_global("wasmer_metering_remaining_points", Type::I64)
+        && exports_global("wasmer_metering_points_exhausted", Type::I32)
+}
+
+impl Metering for Env {
+    fn get_remaining_points(
+        &self,
+        store_mut: &mut StoreMut,
+    ) -> Result<MeteringPoints, wasmer::RuntimeError> {
+        self.metering_globals(store_mut)?
+            .get_remaining_points(store_mut)
+    }
+
+    fn set_remaining_points(
+        &self,
+        store_mut: &mut StoreMut,
+        points: u64,
+    ) -> Result<(), wasmer::RuntimeError> {
+        self.metering_globals(store_mut)?
+            .set_remaining_points(store_mut, points)
+    }
+
+    fn decrease_points(
+        &self,
+        store_mut: &mut StoreMut,
+        points: u64,
+    ) -> Result<MeteringPoints, wasmer::RuntimeError> {
+        self.metering_globals(store_mut)?
+            .decrease_points(store_mut, points)
+    }
+}
+
+impl Env {
+    fn metering_globals(
+        &self,
+        store_mut: &mut StoreMut,
+    ) -> Result<MeteringGlobals<'_>, wasmer::RuntimeError> {
+        if !backend_supports_metering(store_mut.engine()) {
+            return Err(wasm_error!(WasmErrorInner::MeteringUnsupported).into());
+        }
+        Ok(MeteringGlobals {
+            remaining_points: self
+                .wasmer_metering_remaining_points
+                .as_ref()
+                .ok_or(wasm_error!(WasmErrorInner::MissingExport(
+                    "wasmer_metering_remaining_points".to_string()
+                )))?,
+            points_exhausted: self
+                .wasmer_metering_points_exhausted
+                .as_ref()
+                .ok_or(wasm_error!(WasmErrorInner::MissingExport(
+                    "wasmer_metering_points_exhausted".to_string()
+                )))?,
+        })
+    }
+}
+
+/// Metering through the globals exported by metered modules on the sys and
+/// wasmi backends.
+struct MeteringGlobals<'a> {
+    remaining_points: &'a Global,
+    points_exhausted: &'a Global,
+}
+
+impl MeteringGlobals<'_> {
+    fn set(
+        &self,
+        store_mut: &mut StoreMut,
+        remaining_points: u64,
+        points_exhausted: bool,
+    ) -> Result<(), wasmer::RuntimeError> {
+        self.remaining_points
+            .set(store_mut, (remaining_points as i64).into())
+            .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
+        self.points_exhausted
+            .set(store_mut, (points_exhausted as i32).into())
+            .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
+        Ok(())
+    }
+}
+
+impl Metering for MeteringGlobals<'_> {
+    fn get_remaining_points(
+        &self,
+        store_mut: &mut StoreMut,
+    ) -> Result<MeteringPoints, wasmer::RuntimeError> {
+        let exhausted: i32 = self
+            .points_exhausted
+            .get(store_mut)
+            .try_into()
+            .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
+
+        if exhausted > 0 {
+            return Ok(MeteringPoints::Exhausted);
+        }
+
+        let points: i64 = self
+            .remaining_points
+            .get(store_mut)
+            .try_into()
+            .map_err(|_| wasm_error!(WasmErrorInner::PointerMap))?;
+
+        Ok(MeteringPoints::Remaining(points as u64))
+    }
+
+    fn set_remaining_points(
+        &self,
+        store_mut: &mut StoreMut,
+        points: u64,
+    ) -> Result<(), wasmer::RuntimeError> {
+        self.set(store_mut, points, false)
+    }
+
+    fn decrease_points(
+        &self,
+        store_mut: &mut StoreMut,
+        points: u64,
+    ) -> Result<MeteringPoints, wasmer::RuntimeError> {
+        match self.get_remaining_points(store_mut)? {
+            MeteringPoints::Remaining(remaining) if remaining >= points => {
+                self.set(store_mut, remaining - points, false)?;
+                Ok(MeteringPoints::Remaining(remaining - points))
+            }
+            MeteringPoints::Remaining(_) => {
+                self.set(store_mut, 0, true)?;
+                Ok(MeteringPoints::Exhausted)
+            }
+            MeteringPoints::Exhausted => Ok(MeteringPoints::Exhausted),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "wasmer-sys"))]
+mod tests {
+    use super::Metering;
+    use super::MeteringPoints;
+    use crate::prelude::*;
+    use wasmer::AsStoreMut;
+    use wasmer::Global;
+    use wasmer::Store;
+    use wasmer::Value;
+
+    #[test]
+    #[cfg(feature = "wasmer-sys-cranelift")]
+    fn is_metered() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+        let metered = wasmer::Module::new(&crate::module::sys::make_engine(), &wasm).unwrap();
+        let unmetered = wasmer::Module::new(&wasmer::Engine::default(), &wasm).unwrap();
+        assert!(super::is_metered(&metered));
+        assert!(!super::is_metered(&unmetered));
+    }
+
+    #[test]
+    fn env_metering_missing_export() {
+        let mut store = Store::default();
+        let error = Env::default()
+            .get_remaining_points(&mut store.as_store_mut())
+            .unwrap_err();
+        assert_eq!(
+            WasmErrorInner::MissingExport("wasmer_metering_remaining_points".to_string()),
+            error.downcast::<WasmError>().unwrap().error
+        );
+    }
+
+    #[test]
+    fn env_metering() {
+        let mut store = Store::default();
+        let env = Env {
+            wasmer_metering_remaining_points: Some(Global::new_mut(&mut store, Value::I64(10))),
+            wasmer_metering_points_exhausted: Some(Global::new_mut(&mut store, Value::I32(0))),
+            ..Default::default()
+        };
+        let mut store_mut = store.as_store_mut();
+
+        assert_eq!(
+            MeteringPoints::Remaining(10),
+            env.get_remaining_points(&mut store_mut).unwrap()
+        );
+        assert_eq!(
+            MeteringPoints::Remaining(6),
+            env.decrease_points(&mut store_mut, 4).unwrap()
+        );
+        assert_eq!(
+            MeteringPoints::Exhausted,
+            env.decrease_points(&mut store_mut, 7).unwrap()
+        );
+        assert_eq!(
+            MeteringPoints::Exhausted,
+            env.get_remaining_points(&mut store_mut).unwrap()
+        );
+
+        env.set_remaining_points(&mut store_mut, 100).unwrap();
+        assert_eq!(
+            MeteringPoints::Remaining(100),
+            env.get_remaining_points(&mut store_mut).unwrap()
+        );
+    }
+}
@@ -18,29 +18,75 @@
 //! use is made at the call site by passing the appropriate engine
 //! factories to [`ModuleBuilder::new`] (or, for the cached path, to
 //! [`ModuleCache::new`]).
+//!
+//! Once you have a module, [`InstanceBuilder`] instantiates it and wires
+//! up the [`Env`](crate::env::Env) the host functions need. For many short
+//! calls [`InstancePool`] can reuse instances instead.
 
-use crate::plru::MicroCache;
+use crate::plru::DynamicCache;
 use crate::prelude::*;
+use crate::workers::Workers;
 use bimap::BiMap;
-use bytes::BufMut;
 use bytes::Bytes;
-use bytes::BytesMut;
 use parking_lot::Mutex;
 use parking_lot::RwLock;
+use sha2::Digest;
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Write;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::OnceLock;
 use wasmer::Engine;
 use wasmer::Instance;
 use wasmer::Module;
 use wasmer::Store;
 
+mod artifact;
+use artifact::ArtifactHeader;
+
 mod builder;
+mod fingerprint;
+pub use builder::Instrument;
 pub use builder::ModuleBuilder;
 
+mod cost_model;
+pub use cost_model::CostModel;
+
+mod inject;
+mod interrupt;
+pub use interrupt::inject_interrupt;
+pub(crate) use interrupt::is_interruptible;
+pub(crate) use interrupt::CHECK_IMPORT;
+mod memory_limit;
+pub use memory_limit::inject_memory_limit;
+pub(crate) use memory_limit::memory_limit_hits;
+mod metering;
+pub use metering::inject_metering;
+pub use metering::WASM_METERING_LIMIT;
+
+mod instance;
+pub use instance::InstanceBuilder;
+
+mod precompile;
+pub use precompile::PrecompileHandle;
+
+mod prune;
+pub use prune::FilesystemBudget;
+pub use prune::Pruned;
+
+mod stats;
+use stats::Counters;
+pub use stats::ModuleCacheStats;
+
+mod store;
+pub use store::FilesystemModuleStore;
+pub use store::SerializedModuleStore;
+
+mod pool;
+pub use pool::InstancePool;
+pub use pool::PoolImports;
+pub use pool::PooledInstance;
+pub use pool::DEFAULT_MAX_IDLE;
+
 #[cfg(feature = "wasmer-sys")]
 pub mod sys;
 
@@ -50,16 +96,42 @@ pub mod wasmi;
 /// We expect cache keys to be produced via hashing so 32 bytes is enough for all
 /// purposes.
 pub type CacheKey = [u8; 32];
+
+/// The cache key of `wasm` by its content, a SHA-256 hash of the wasm bytes,
+/// see [`ModuleCache::get_by_content`].
+pub fn content_key(wasm: &[u8]) -> CacheKey {
+    sha2::Sha256::digest(wasm).into()
+}
+
 /// Plru uses a usize to track "recently used" so we need a map between 32 byte cache
 /// keys and the bits used to evict things from the cache.
 type PlruKeyMap = BiMap<usize, CacheKey>;
 
+/// Number of modules a [`ModuleCache`] keeps in memory unless constructed with
+/// a different capacity, e.g. by [`ModuleCache::new_with_capacity`].
+pub const DEFAULT_MODULE_CACHE_CAPACITY: usize = 64;
+
+/// Number of bytes of a [`ModuleBuilder::fingerprint`] that name the
+/// filesystem cache directory of the builder's modules.
+pub(crate) const FINGERPRINT_DIR_LEN: usize = 8;
+
 #[derive(Clone, Debug)]
 pub struct InstanceWithStore {
     pub store: Arc<Mutex<Store>>,
     pub instance: Arc<Instance>,
 }
 
+/// An instance with a store that can call it asynchronously, see
+/// [`InstanceBuilder::build_async`].
+///
+/// Copies of the store can be made with [`wasmer::AsStoreAsync::store`].
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct InstanceWithStoreAsync {
+    pub store: wasmer::StoreAsync,
+    pub instance: Arc<Instance>,
+}
+
 /// Higher level trait over the plru cache to make it a bit easier to interact
 /// with consistently. Default implementations for key functions are provided.
 /// Notably handles keeping the mapping between cache keys and items, and the
@@ -68,7 +140,7 @@ trait PlruCache {
     /// The type of items in the cache.
     type Item;
     /// Accessor for mutable reference to internal plru cache.
-    fn plru_mut(&mut self) -> &mut MicroCache;
+    fn plru_mut(&mut self) -> &mut DynamicCache;
     /// Accessor to mapping between plru cache bits and cache keys.
     fn key_map(&self) -> &PlruKeyMap;
     /// Mutable accessor to mapping between plru cache bits and cache keys.
@@ -118,23 +190,108 @@ trait PlruCache {
         }
         maybe_item
     }
+
+    /// Remove an item from the cache by its cache key, freeing up its plru
+    /// slot for the next item put in the cache.
+    fn remove_item(&mut self, key: &CacheKey) -> Option<Arc<Self::Item>> {
+        let item = self.cache_mut().remove(key)?;
+        if let Some((plru_key, _)) = self.key_map_mut().remove_by_right(key) {
+            self.plru_mut().trash(plru_key);
+        }
+        Some(item)
+    }
+}
+
+/// A module in the [`InMemoryModuleCache`] along with the size of its
+/// serialized form, which is the best measure we have of the memory it takes,
+/// or of its wasm if it was never serialized, see [`ModuleCache::memory_budget`].
+#[derive(Debug)]
+struct CachedModule {
+    module: Arc<Module>,
+    size: usize,
 }
 
 /// Caches deserialized wasm modules.
 ///
 /// Deserialization of cached modules from the cache to create callable instances is slow.
 /// Therefore modules are cached in memory after deserialization.
-#[derive(Default, Debug)]
+#[derive(Debug)]
 struct InMemoryModuleCache {
-    plru: MicroCache,
+    plru: DynamicCache,
     key_map: PlruKeyMap,
-    cache: BTreeMap<CacheKey, Arc<Module>>,
+    cache: BTreeMap<CacheKey, Arc<CachedModule>>,
+    // Total size of cached modules to evict down to, if any.
+    memory_budget: Option<usize>,
+    // Number of modules evicted so far.
+    evictions: u64,
+}
+
+impl InMemoryModuleCache {
+    /// The plru cache has whole blocks of 64 lines so the capacity is rounded
+    /// up to a multiple of 64, and is at least 64.
+    fn new(capacity: usize) -> Self {
+        Self {
+            plru: crate::plru::create(capacity.max(1)),
+            key_map: PlruKeyMap::default(),
+            cache: BTreeMap::default(),
+            memory_budget: None,
+            evictions: 0,
+        }
+    }
+
+    /// Total size of all cached modules.
+    fn resident_size(&self) -> usize {
+        self.cache.values().map(|cached| cached.size).sum()
+    }
+
+    /// Drop all cached modules, keeping the capacity and memory budget.
+    fn clear(&mut self) {
+        self.plru = crate::plru::create(self.plru.len());
+        self.key_map.clear();
+        self.cache.clear();
+    }
+
+    /// Put a module in the cache, then evict other modules until the cache is
+    /// within its memory budget again. Cold modules are evicted first.
+    ///
+    /// The new module itself is never evicted, even if it alone is over the
+    /// budget, as it is about to be used.
+    fn put_module(&mut self, key: CacheKey, module: Arc<Module>, size: usize) {
+        let expected_len = self.cache.len() + usize::from(!self.cache.contains_key(&key));
+        self.put_item(key, Arc::new(CachedModule { module, size }));
+        self.evictions += (expected_len - self.cache.len()) as u64;
+        let Some(memory_budget) = self.memory_budget else {
+            return;
+        };
+        let mut resident_size = self.resident_size();
+        while resident_size > memory_budget {
+            let Some(stale_key) = self
+                .key_map
+                .iter()
+                .filter(|(_, cache_key)| **cache_key != key)
+                .min_by_key(|(plru_key, _)| self.plru.is_hot(**plru_key))
+                .map(|(_, cache_key)| *cache_key)
+            else {
+                break;
+            };
+            if let Some(stale) = self.remove_item(&stale_key) {
+                resident_size -= stale.size;
+                self.evictions += 1;
+            }
+        }
+    }
+}
+
+impl Default for InMemoryModuleCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_MODULE_CACHE_CAPACITY)
+    }
 }
 
 impl PlruCache for InMemoryModuleCache {
-    type Item = Module;
+    type Item = CachedModule;
 
-    fn plru_mut(&mut self) -> &mut MicroCache {
+    fn plru_mut(&mut self) -> &mut DynamicCache {
         &mut self.plru
     }
 
@@ -160,10 +317,14 @@ pub struct ModuleCache {
     // The in-memory cache of deserialized modules
     cache: Arc<RwLock<InMemoryModuleCache>>,
 
-    // Filesystem path where serialized modules are cached.
+    // Where serialized modules are cached, by default on the filesystem.
     //
     // A serialized wasm module must still be deserialized before it can be used to build instances.
     // The deserialization process is far faster than compiling and much slower than instance building.
+    store: Option<Arc<dyn SerializedModuleStore>>,
+
+    // Filesystem path of the default store, kept to rebuild it with a
+    // budget. `None` when there is no store or a custom one.
     filesystem_path: Option<PathBuf>,
 
     // Handles building wasm modules
@@ -171,6 +332,41 @@ pub struct ModuleCache {
     // It includes the runtime engine that must live as long as the module,
     // so we keep it in the cache and use for all modules.
     builder: ModuleBuilder,
+
+    // Counts what the cache has been doing, see `Self::stats`.
+    counters: Counters,
+
+    // Header of the serialized modules this cache reads and writes, built on
+    // first use as it needs the builder's fingerprint.
+    artifact_header: OnceLock<ArtifactHeader>,
+
+    // Key for the HMAC of serialized modules, see `Self::integrity_key`.
+    integrity_key: Option<IntegrityKey>,
+
+    // Modules being loaded or compiled right now, so that concurrent gets of
+    // the same module wait for one load rather than all compiling it.
+    in_flight: Mutex<BTreeMap<CacheKey, InFlight>>,
+
+    // The content key of the wasm each cache key was first used with, only
+    // kept in debug builds and bounded by the capacity, see
+    // `Self::check_content_key`.
+    content_keys: Mutex<BTreeMap<CacheKey, CacheKey>>,
+
+    // Threads for `Self::precompile`, started along with the first job.
+    workers: OnceLock<Workers>,
+    precompile_threads: usize,
+}
+
+/// The result of loading a module, shared by everyone waiting for it.
+type InFlight = Arc<OnceLock<Result<Arc<Module>, wasmer::RuntimeError>>>;
+
+/// Keeps the key out of debug output.
+struct IntegrityKey(Vec<u8>);
+
+impl std::fmt::Debug for IntegrityKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("IntegrityKey(..)")
+    }
 }
 
 impl ModuleCache {
@@ -226,55 +422,432 @@ impl ModuleCache {
 
     /// Construct a ModuleCache with a custom ModuleBuilder
     pub fn new_with_builder(builder: ModuleBuilder, filesystem_path: Option<PathBuf>) -> Self {
-        let cache = Arc::new(RwLock::new(InMemoryModuleCache::default()));
+        Self::new_with_builder_and_capacity(builder, filesystem_path, DEFAULT_MODULE_CACHE_CAPACITY)
+    }
+
+    /// Same as [`Self::new`] but keeps up to `capacity` modules in memory
+    /// rather than [`DEFAULT_MODULE_CACHE_CAPACITY`].
+    ///
+    /// Hosts running many different wasms should size this to the number of
+    /// wasms they call regularly, otherwise modules are evicted and have to be
+    /// deserialized from the filesystem, or even recompiled, over and over.
+    ///
+    /// Modules are evicted by a pseudo-LRU policy, which tracks whole blocks
+    /// of 64 modules, so the capacity is rounded up to a multiple of 64.
+    pub fn new_with_capacity(
+        make_engine: fn() -> Engine,
+        make_runtime_engine: fn() -> Engine,
+        filesystem_path: Option<PathBuf>,
+        capacity: usize,
+    ) -> Self {
+        Self::new_with_builder_and_capacity(
+            ModuleBuilder::new(make_engine, make_runtime_engine),
+            filesystem_path,
+            capacity,
+        )
+    }
+
+    /// Construct a ModuleCache with a custom ModuleBuilder that keeps up to
+    /// `capacity` modules in memory, see [`Self::new_with_capacity`].
+    pub fn new_with_builder_and_capacity(
+        builder: ModuleBuilder,
+        filesystem_path: Option<PathBuf>,
+        capacity: usize,
+    ) -> Self {
+        let cache = Arc::new(RwLock::new(InMemoryModuleCache::new(capacity)));
+        let store = filesystem_path.clone().map(|filesystem_path| {
+            Arc::new(FilesystemModuleStore::new(filesystem_path, &builder))
+                as Arc<dyn SerializedModuleStore>
+        });
         ModuleCache {
             cache,
+            store,
             filesystem_path,
             builder,
+            counters: Counters::default(),
+            artifact_header: OnceLock::new(),
+            integrity_key: None,
+            in_flight: Mutex::new(BTreeMap::new()),
+            content_keys: Mutex::new(BTreeMap::new()),
+            workers: OnceLock::new(),
+            precompile_threads: std::thread::available_parallelism().map_or(1, usize::from),
+        }
+    }
+
+    /// The number of modules kept in memory before the least recently used
+    /// ones are evicted.
+    pub fn capacity(&self) -> usize {
+        self.cache.read().plru.len()
+    }
+
+    /// Evict modules from memory whenever their total serialized size goes
+    /// over `memory_budget` bytes, on top of the limit on their number.
+    ///
+    /// Serialized size is only a rough measure of the memory a module takes,
+    /// but one giant module now counts for more than many tiny ones. Caches
+    /// without a filesystem path or store never serialize the modules they
+    /// compile, so those count with the size of their wasm instead. Compiled
+    /// code is typically several times bigger than its wasm, so the same
+    /// budget keeps more modules in memory in such a cache.
+    pub fn memory_budget(self, memory_budget: usize) -> Self {
+        self.cache.write().memory_budget = Some(memory_budget);
+        self
+    }
+
+    /// Sign serialized modules written to the filesystem with an HMAC under
+    /// `key`, and only load modules signed with the same key.
+    ///
+    /// Without a key modules are only checked against a plain digest, which
+    /// catches corruption but not deliberate tampering. With a key, whoever
+    /// can write to the filesystem cache can't get the host to run their own
+    /// machine code without also knowing the key. Modules written without
+    /// the key, or with another key, are removed and compiled again.
+    pub fn integrity_key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.integrity_key = Some(IntegrityKey(key.into()));
+        self
+    }
+
+    /// Keep serialized modules in `store` rather than on the filesystem,
+    /// e.g. in a key-value database the host already has.
+    ///
+    /// This replaces the filesystem path the cache was constructed with, if
+    /// any.
+    pub fn serialized_module_store(mut self, store: Arc<dyn SerializedModuleStore>) -> Self {
+        self.store = Some(store);
+        self.filesystem_path = None;
+        self
+    }
+
+    /// Precompile modules on `threads` threads rather than one per CPU, see
+    /// [`Self::precompile`].
+    pub fn precompile_threads(mut self, threads: usize) -> Self {
+        self.precompile_threads = threads;
+        self
+    }
+
+    /// Remove the least recently used serialized modules from the filesystem
+    /// whenever they go over `budget`, see [`Self::prune`].
+    ///
+    /// This only applies to the filesystem path the cache was constructed
+    /// with. Custom stores are configured on the store itself, e.g. with
+    /// [`FilesystemModuleStore::budget`].
+    pub fn filesystem_budget(mut self, budget: FilesystemBudget) -> Self {
+        if let Some(filesystem_path) = &self.filesystem_path {
+            self.store = Some(Arc::new(
+                FilesystemModuleStore::new(filesystem_path.clone(), &self.builder).budget(budget),
+            ));
+        }
+        self
+    }
+
+    /// Remove serialized modules from the filesystem until they fit in the
+    /// [`Self::filesystem_budget`], if any, e.g. once at startup.
+    ///
+    /// This happens after every module written to the filesystem anyway, but
+    /// modules are only written when they are compiled, so a cache that finds
+    /// everything it needs on the filesystem never prunes by itself.
+    ///
+    /// Modules serialized by builders with other fingerprints, e.g. from
+    /// before a wasmer upgrade, count towards the budget too and as they are
+    /// never used again they are the first to go. So do modules written
+    /// straight into the filesystem path by versions that didn't keep modules
+    /// apart by fingerprint. Temporary files left by interrupted writes are
+    /// removed as well.
+    pub fn prune(&self) -> Result<Pruned, std::io::Error> {
+        match &self.store {
+            Some(store) => store.prune(),
+            None => Ok(Pruned::default()),
+        }
+    }
+
+    /// Total serialized size of the modules currently kept in memory, or the
+    /// size of their wasm for modules that were never serialized, see
+    /// [`Self::memory_budget`].
+    pub fn resident_size(&self) -> usize {
+        self.cache.read().resident_size()
+    }
+
+    /// A snapshot of the counters of this cache and of what it currently
+    /// keeps in memory.
+    pub fn stats(&self) -> ModuleCacheStats {
+        let cache = self.cache.read();
+        ModuleCacheStats {
+            evictions: cache.evictions,
+            modules: cache.cache.len(),
+            resident_size: cache.resident_size(),
+            capacity: cache.plru.len(),
+            ..self.counters.stats()
+        }
+    }
+
+    /// Keys of the modules currently kept in memory, in no particular order.
+    pub fn keys(&self) -> Vec<CacheKey> {
+        self.cache.read().cache.keys().copied().collect()
+    }
+
+    /// Whether the module for `key` is currently kept in memory.
+    ///
+    /// Unlike [`Self::get`] this doesn't count as a use of the module, so it
+    /// doesn't keep the module from being evicted.
+    pub fn contains(&self, key: &CacheKey) -> bool {
+        self.cache.read().cache.contains_key(key)
+    }
+
+    /// Remove the module for `key` from memory and from the filesystem, e.g.
+    /// when the wasm it was built from is no longer in use.
+    ///
+    /// Instances already built from the module keep working, but the next
+    /// [`Self::get`] compiles the module again.
+    pub fn remove(&self, key: CacheKey) -> Result<(), std::io::Error> {
+        self.cache.write().remove_item(&key);
+        self.content_keys.lock().remove(&key);
+        self.remove_from_store(key)
+    }
+
+    /// Remove all modules from memory. The filesystem is left alone, see
+    /// [`Self::clear_filesystem`].
+    pub fn clear(&self) {
+        self.cache.write().clear();
+        self.content_keys.lock().clear();
+    }
+
+    /// Remove all serialized modules from the filesystem. Modules in memory
+    /// are left alone, see [`Self::clear`].
+    ///
+    /// Only files named like the cache keys of this cache are removed, so
+    /// anything else that happens to be in the directory is safe. Modules
+    /// serialized by builders with another [`ModuleBuilder::fingerprint`] are
+    /// kept too, while modules from before modules were kept apart by
+    /// fingerprint are removed. With a custom [`Self::serialized_module_store`]
+    /// it is up to the store, see [`SerializedModuleStore::clear`].
+    pub fn clear_filesystem(&self) -> Result<(), std::io::Error> {
+        match &self.store {
+            Some(store) => store.clear(),
+            None => Ok(()),
         }
     }
 
     /// Get a module from the cache, or add it to both caches if not found
+    ///
+    /// Concurrent gets of a module that isn't in memory wait for a single
+    /// load or compilation of it and all get the same module.
+    ///
+    /// `key` must identify `wasm`, usually by hashing it, as the module cached
+    /// for a key is returned no matter what wasm comes with it. Debug builds
+    /// log an error when a key that misses the in-memory cache comes with
+    /// other wasm than it did before. See
+    /// [`Self::get_by_content`] for the cache working out the key itself.
     pub fn get(&self, key: CacheKey, wasm: &[u8]) -> Result<Arc<Module>, wasmer::RuntimeError> {
         // Check in-memory cache for module
         if let Some(module) = self.get_from_cache(key) {
+            Counters::increment(&self.counters.hits);
             return Ok(module);
         }
+        Counters::increment(&self.counters.misses);
+        self.check_content_key(key, wasm);
+
+        let (result, _) = self.single_flight(key, || {
+            // Whoever loaded the module before us may have finished
+            // between our miss and taking the lock.
+            match self.get_from_cache(key) {
+                Some(module) => Ok(module),
+                None => self.load(key, wasm),
+            }
+        });
+        result
+    }
+
+    /// [`Self::get`] with the [`content_key`] of `wasm` as its key, so the key
+    /// can't be wrong.
+    ///
+    /// This hashes all of `wasm` on every call, so callers that already have
+    /// a hash of the wasm, such as a DNA hash, may want to use that instead.
+    pub fn get_by_content(&self, wasm: &[u8]) -> Result<Arc<Module>, wasmer::RuntimeError> {
+        self.get(content_key(wasm), wasm)
+    }
+
+    /// Get a module into the cache in the background, so that the first
+    /// [`Self::get`] doesn't have to compile it, e.g. when a wasm is
+    /// installed.
+    ///
+    /// The module is loaded or compiled on one of the cache's
+    /// [`Self::precompile_threads`] exactly like [`Self::get`] would, and a
+    /// `get` of the same module in the meantime waits for it rather than
+    /// compiling it again.
+    pub fn precompile(self: &Arc<Self>, key: CacheKey, wasm: impl Into<Bytes>) -> PrecompileHandle {
+        let handle = PrecompileHandle::new(key);
+        let wasm = wasm.into();
+        let job = {
+            let cache = self.clone();
+            let handle = handle.clone();
+            move || {
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    cache.get(key, &wasm)
+                }))
+                .unwrap_or_else(|_| {
+                    Err(wasm_error!(WasmErrorInner::ModuleBuild(
+                        "Panicked while precompiling".to_string()
+                    ))
+                    .into())
+                });
+                handle.finish(result);
+            }
+        };
+        let workers = self
+            .workers
+            .get_or_init(|| Workers::new("wasm-precompile", self.precompile_threads));
+        if let Err(e) = workers.run(job) {
+            handle.finish(Err(wasm_error!(WasmErrorInner::ModuleBuild(format!(
+                "Failed to start a precompile thread: {e}"
+            )))
+            .into()));
+        }
+        handle
+    }
+
+    /// [`Self::precompile`] each of `wasms` by their cache keys.
+    pub fn warm<W: Into<Bytes>>(
+        self: &Arc<Self>,
+        wasms: impl IntoIterator<Item = (CacheKey, W)>,
+    ) -> Vec<PrecompileHandle> {
+        wasms
+            .into_iter()
+            .map(|(key, wasm)| self.precompile(key, wasm))
+            .collect()
+    }
+
+    /// Install an artifact compiled ahead of time by
+    /// [`ModuleBuilder::compile_to_artifact`] as the module for `key`, both in
+    /// memory and on the filesystem, so that [`Self::get`] doesn't have to
+    /// compile it.
+    ///
+    /// The artifact header is checked just like for modules the cache reads
+    /// from the filesystem: it must have been built by the same wasmer
+    /// version for the same target, by a builder with the same backend and
+    /// [`ModuleBuilder::fingerprint`] as this cache's builder. Artifacts
+    /// built with other metering or instrumentation are refused.
+    ///
+    /// Any module already cached for `key` is replaced. A [`Self::get`] of
+    /// `key` that is loading the module at the same time finishes first.
+    pub fn import_artifact(
+        &self,
+        key: CacheKey,
+        artifact: Bytes,
+    ) -> Result<Arc<Module>, wasmer::RuntimeError> {
+        loop {
+            let (result, ours) =
+                self.single_flight(key, || self.install_artifact(key, artifact.clone()));
+            if ours {
+                return result;
+            }
+        }
+    }
+
+    /// Run `load` for `key` unless a load of the same key is already in
+    /// flight, in which case wait for that one instead.
+    ///
+    /// Returns the result of whichever load ran, and whether it was `load`.
+    fn single_flight(
+        &self,
+        key: CacheKey,
+        load: impl FnOnce() -> Result<Arc<Module>, wasmer::RuntimeError>,
+    ) -> (Result<Arc<Module>, wasmer::RuntimeError>, bool) {
+        let in_flight = self.in_flight.lock().entry(key).or_default().clone();
+        let mut ours = false;
+        let result = in_flight
+            .get_or_init(|| {
+                ours = true;
+                load()
+            })
+            .clone();
+        // Only the first to get here removes the entry, a later one may
+        // belong to a load started after this one finished.
+        let mut in_flight_map = self.in_flight.lock();
+        if in_flight_map
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, &in_flight))
+        {
+            in_flight_map.remove(&key);
+        }
+        (result, ours)
+    }
+
+    /// Check an artifact and make it the module for `key`, see
+    /// [`Self::import_artifact`].
+    fn install_artifact(
+        &self,
+        key: CacheKey,
+        artifact: Bytes,
+    ) -> Result<Arc<Module>, wasmer::RuntimeError> {
+        let serialized_module = artifact::open(self.artifact_header(), None, artifact)
+            .map_err(|e| wasm_error!(WasmErrorInner::ModuleDeserialize(e)))?;
+        let module = self
+            .builder
+            .from_serialized_module(serialized_module.clone())?;
 
+        // Stores only write modules once, so make way for the new one.
+        if let Err(e) = self.remove_from_store(key) {
+            tracing::debug!(
+                "Failed to remove cached wasm with cache key {:?}: {:?}",
+                key,
+                e
+            );
+        }
+        self.add_to_store(key, &serialized_module);
+        self.add_to_cache(key, module.clone(), serialized_module.len());
+        Ok(module)
+    }
+
+    /// Load a module from the filesystem, or build it and add it to both
+    /// caches if not found
+    fn load(&self, key: CacheKey, wasm: &[u8]) -> Result<Arc<Module>, wasmer::RuntimeError> {
         // Check the filesystem for module
-        match self.get_from_filesystem(key) {
+        match self.get_from_store(key) {
             // Filesystem cache hit, deserialize and save to cache
-            Ok(Some(serialized_module)) => {
-                let module_result = self.builder.from_serialized_module(serialized_module);
-
-                // If deserialization fails, we assume the file is corrupt,
-                // so it is removed from the filesystem cache,
-                // and the wasm is re-added to the cache again.
-                let module = match module_result {
-                    Ok(d) => Ok(d),
-                    Err(_) => {
+            Some(artifact) => {
+                let module_result = self.open_artifact(artifact).and_then(|serialized_module| {
+                    let size = serialized_module.len();
+                    Ok((
+                        self.builder.from_serialized_module(serialized_module)?,
+                        size,
+                    ))
+                });
+
+                // If the header doesn't check out or deserialization fails,
+                // we assume the file is corrupt, so it is removed from the
+                // filesystem cache, and the wasm is re-added to the cache again.
+                match module_result {
+                    Ok((module, size)) => {
+                        Counters::increment(&self.counters.filesystem_hits);
+                        self.add_to_cache(key, module.clone(), size);
+                        Ok(module)
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            "Discarding cached wasm from filesystem with cache key {:?}: {:?}",
+                            key,
+                            e
+                        );
+                        Counters::increment(&self.counters.corrupt_files);
+
                         // Remove from filesystem
-                        if let Err(e) = self.remove_from_filesystem(key) {
+                        if let Err(e) = self.remove_from_store(key) {
                             tracing::debug!("Failed to remove cached wasm from filesystem with cache key {:?}: {:?}", key, e);
                         }
 
                         // Build wasm and save to both caches
-                        self.add_to_cache_and_filesystem(key, wasm)
+                        self.add_to_cache_and_store(key, wasm)
                     }
-                }?;
-
-                self.add_to_cache(key, module.clone());
-
-                Ok(module)
+                }
             }
 
             // Filesystem cache miss, build wasm and save to both caches
-            _ => self.add_to_cache_and_filesystem(key, wasm),
+            None => self.add_to_cache_and_store(key, wasm),
         }
     }
 
     /// Build a wasm, then save it to both the in-memory cache and filesystem
-    fn add_to_cache_and_filesystem(
+    fn add_to_cache_and_store(
         &self,
         key: CacheKey,
         wasm: &[u8],
@@ -282,7 +855,20 @@ impl ModuleCache {
         // Each module needs to be compiled with a new engine because
         // of middleware like metering. Middleware is compiled into the
         // module once and available in all instances created from it.
+        let started = std::time::Instant::now();
+
+        // Without a store there is nothing to serialize the module for, so
+        // it is used as compiled and the builder keeps its engine alive.
+        // The wasm stands in for the serialized size.
+        if self.store.is_none() {
+            let module = self.builder.from_binary_keeping_engine(wasm)?;
+            self.counters.add_compile(started.elapsed());
+            self.add_to_cache(key, module.clone(), wasm.len());
+            return Ok(module);
+        }
+
         let module = self.builder.from_binary(wasm)?;
+        self.counters.add_compile(started.elapsed());
 
         // Round trip the wasmer Module through serialization.
         //
@@ -291,12 +877,14 @@ impl ModuleCache {
         // the module from the engine that was used for compilation.
         // After that another engine can be used to deserialize the
         // module again. The engine has to live as long as the module to
-        // prevent memory access out of bounds errors.
+        // prevent memory access out of bounds errors. The serialized
+        // module has to be made for the store anyway, and this way the
+        // code of all modules ends up in the one runtime engine.
         //
         // This procedure facilitates caching of modules that can be
         // instantiated with fresh stores free from state. Instance
-        // creation is highly performant which makes caching of instances
-        // and stores unnecessary.
+        // creation is much faster than compilation but still dominates
+        // short calls, see `InstancePool` for reusing instances.
         //
         // See https://github.com/wasmerio/wasmer/discussions/3829#discussioncomment-5790763
         let serialized_module = module
@@ -307,98 +895,209 @@ impl ModuleCache {
             .from_serialized_module(serialized_module.clone())?;
 
         // Save serialized module to filesystem cache
-        self.add_to_filesystem(key, serialized_module)?;
+        let size = serialized_module.len();
+        self.add_to_store(key, &serialized_module);
 
         // Save module to in-memory cache
-        self.add_to_cache(key, module.clone());
+        self.add_to_cache(key, module.clone(), size);
 
         Ok(module)
     }
 
-    /// Get serialized module from filesystem
-    fn get_from_filesystem(&self, key: CacheKey) -> Result<Option<Bytes>, wasmer::RuntimeError> {
-        self.filesystem_module_path(key)
-            .as_ref()
-            .map(|module_path| {
-                // Read file into `Bytes` instead of `Vec<u8>` so that the clone is cheap
-                let mut file = File::open(module_path).map_err(|e| {
-                    wasm_error!(WasmErrorInner::ModuleBuild(format!(
-                        "{} Path: {}",
-                        e,
-                        module_path.display()
-                    )))
-                })?;
-
-                let mut bytes_mut = BytesMut::new().writer();
-                std::io::copy(&mut file, &mut bytes_mut).map_err(|e| {
-                    wasm_error!(WasmErrorInner::ModuleBuild(format!(
-                        "{} Path: {}",
-                        e,
-                        module_path.display()
-                    )))
-                })?;
-
-                Ok::<bytes::Bytes, wasmer::RuntimeError>(bytes_mut.into_inner().freeze())
-            })
-            .transpose()
+    /// Get serialized module from the store
+    ///
+    /// A store that fails to read is treated like one that doesn't have the
+    /// module, so the module is compiled again.
+    fn get_from_store(&self, key: CacheKey) -> Option<Bytes> {
+        match self.store.as_ref()?.get(&key) {
+            Ok(artifact) => artifact,
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to read cached wasm with cache key {:?}: {:?}",
+                    key,
+                    e
+                );
+                None
+            }
+        }
     }
 
-    /// Add serialized module to filesystem cache
-    fn add_to_filesystem(
-        &self,
-        key: CacheKey,
-        serialized_module: Bytes,
-    ) -> Result<(), wasmer::RuntimeError> {
-        if let Some(fs_path) = self.filesystem_module_path(key) {
-            match OpenOptions::new()
-                .write(true)
-                // Using create_new here so that cache stampedes don't
-                // cause corruption. Each file can only be written once.
-                .create_new(true)
-                .open(&fs_path)
-            {
-                Ok(mut file) => {
-                    if let Err(e) = file.write_all(&serialized_module) {
-                        tracing::error!("{} Path: {}", e, fs_path.display());
-                    }
+    /// Add serialized module to the store
+    ///
+    /// The module is already usable from memory, so failing to write it is
+    /// not an error for the caller. Failures are counted in
+    /// [`ModuleCacheStats::filesystem_write_errors`] instead.
+    fn add_to_store(&self, key: CacheKey, serialized_module: &[u8]) {
+        if let Some(store) = &self.store {
+            match store.put(&key, &self.seal_artifact(serialized_module)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    // This is just a debug because it is expected that
+                    // other caches sharing the store, e.g. in other
+                    // processes, will sometimes build the same wasm.
+                    tracing::debug!("{} Cache key: {:?}", e, key);
                 }
                 Err(e) => {
-                    // This is just a warning because it is expected that
-                    // multiple concurrent calls to build the same wasm
-                    // will sometimes happen.
-                    tracing::warn!("{} Path: {}", e, fs_path.display());
+                    Counters::increment(&self.counters.filesystem_write_errors);
+                    tracing::error!("{} Cache key: {:?}", e, key);
                 }
             }
         }
+    }
 
-        Ok(())
+    /// Prefix a serialized module with the header checked by
+    /// [`Self::open_artifact`].
+    fn seal_artifact(&self, serialized_module: &[u8]) -> Vec<u8> {
+        artifact::seal(
+            self.artifact_header(),
+            self.integrity_key.as_ref().map(|key| key.0.as_slice()),
+            serialized_module,
+        )
     }
 
-    // Remove serialized module from filesystem cache
-    fn remove_from_filesystem(&self, key: CacheKey) -> Result<(), std::io::Error> {
-        if let Some(fs_path) = self.filesystem_module_path(key) {
-            std::fs::remove_file(fs_path)?;
+    /// Get the serialized module out of a file written by
+    /// [`Self::seal_artifact`], if it was written by a cache like this one
+    /// and hasn't been changed since.
+    fn open_artifact(&self, artifact: Bytes) -> Result<Bytes, wasmer::RuntimeError> {
+        artifact::open(
+            self.artifact_header(),
+            self.integrity_key.as_ref().map(|key| key.0.as_slice()),
+            artifact,
+        )
+        .map_err(|e| wasm_error!(WasmErrorInner::ModuleDeserialize(e)).into())
+    }
+
+    fn artifact_header(&self) -> &ArtifactHeader {
+        self.artifact_header.get_or_init(|| {
+            ArtifactHeader::new(
+                self.builder.backend().to_string(),
+                self.builder.fingerprint(),
+            )
+        })
+    }
+
+    // Remove serialized module from the store
+    fn remove_from_store(&self, key: CacheKey) -> Result<(), std::io::Error> {
+        match &self.store {
+            Some(store) => store.remove(&key),
+            None => Ok(()),
         }
+    }
 
-        Ok(())
+    /// Check that `key` is only ever used for the same wasm, logging an
+    /// error if not. Only debug builds check, as it hashes all of the wasm,
+    /// and `Self::get` only checks when the module isn't in memory.
+    ///
+    /// Once there are more keys than the cache has room for modules, keys
+    /// whose modules were evicted are forgotten.
+    fn check_content_key(&self, key: CacheKey, wasm: &[u8]) -> bool {
+        if !cfg!(debug_assertions) {
+            return true;
+        }
+        let content_key = content_key(wasm);
+        let mut content_keys = self.content_keys.lock();
+        let first_content_key = *content_keys.entry(key).or_insert(content_key);
+        if content_keys.len() > self.capacity() {
+            let cache = self.cache.read();
+            content_keys.retain(|k, _| *k == key || cache.cache.contains_key(k));
+        }
+        if first_content_key != content_key {
+            tracing::error!(
+                "Cache key {} was used for different wasms, the module cached for it may not match the wasm",
+                hex::encode(key)
+            );
+            return false;
+        }
+        true
     }
 
     /// Check cache for module
     fn get_from_cache(&self, key: CacheKey) -> Option<Arc<Module>> {
         let mut cache = self.cache.write();
-        cache.get_item(&key)
+        cache.get_item(&key).map(|cached| cached.module.clone())
     }
 
-    /// Add module to cache
-    fn add_to_cache(&self, key: CacheKey, module: Arc<Module>) {
+    /// Add module to cache along with its serialized size
+    fn add_to_cache(&self, key: CacheKey, module: Arc<Module>, size: usize) {
         let mut cache = self.cache.write();
-        cache.put_item(key, module.clone());
+        cache.put_module(key, module, size);
+    }
+}
+
+#[cfg(all(test, feature = "wasmer-sys-cranelift"))]
+mod tests {
+    use super::sys;
+    use super::CachedModule;
+    use super::InMemoryModuleCache;
+    use super::ModuleCache;
+    use super::PlruCache;
+    use std::sync::Arc;
+    use wasmer::Engine;
+    use wasmer::Module;
+
+    #[test]
+    fn in_memory_capacity() {
+        let engine = Engine::default();
+        let module = Arc::new(Module::new(&engine, "(module)").unwrap());
+
+        for (capacity, expected) in [(0, 64), (64, 64), (100, 128), (1000, 1024)] {
+            let mut cache = InMemoryModuleCache::new(capacity);
+            for i in 0..2000u16 {
+                let mut key = [0; 32];
+                key[..2].copy_from_slice(&i.to_le_bytes());
+                let cached = CachedModule {
+                    module: module.clone(),
+                    size: 0,
+                };
+                cache.put_item(key, Arc::new(cached));
+            }
+            assert_eq!(expected, cache.plru.len());
+            assert_eq!(expected, cache.cache.len());
+        }
+    }
+
+    #[test]
+    fn in_memory_budget() {
+        let engine = Engine::default();
+        let module = Arc::new(Module::new(&engine, "(module)").unwrap());
+        let mut cache = InMemoryModuleCache::new(64);
+        cache.memory_budget = Some(100);
+
+        cache.put_module([1; 32], module.clone(), 40);
+        cache.put_module([2; 32], module.clone(), 40);
+        assert_eq!(80, cache.resident_size());
+
+        // Both older modules are hot, so either may go.
+        cache.put_module([3; 32], module.clone(), 40);
+        assert_eq!(80, cache.resident_size());
+        assert!(cache.cache.contains_key(&[3; 32]));
+
+        // Too big for the budget but kept anyway.
+        cache.put_module([4; 32], module.clone(), 200);
+        assert_eq!(200, cache.resident_size());
+        assert_eq!(1, cache.cache.len());
+        assert_eq!(1, cache.key_map.len());
+        assert_eq!(3, cache.evictions);
     }
 
-    /// Get filesystem cache path for a given key
-    fn filesystem_module_path(&self, key: CacheKey) -> Option<PathBuf> {
-        self.filesystem_path
-            .as_ref()
-            .map(|dir_path| dir_path.clone().join(hex::encode(key)))
+    #[test]
+    #[cfg(debug_assertions)]
+    fn content_key_mismatch() {
+        let cache = ModuleCache::new(sys::make_engine, sys::make_runtime_engine, None);
+        let key = [0; 32];
+        assert!(cache.check_content_key(key, b"a"));
+        assert!(cache.check_content_key(key, b"a"));
+        assert!(!cache.check_content_key(key, b"b"));
+
+        cache.clear();
+        assert!(cache.check_content_key(key, b"b"));
+
+        // Keys of modules that aren't cached don't pile up.
+        for i in 0..1000u16 {
+            let mut key = [0; 32];
+            key[..2].copy_from_slice(&i.to_le_bytes());
+            cache.check_content_key(key, b"a");
+        }
+        assert!(cache.content_keys.lock().len() <= cache.capacity() + 1);
     }
 }
@@ -0,0 +1,228 @@
+//! The format of serialized modules in the filesystem cache.
+//!
+//! Deserializing a module loads machine code without validating it, so the
+//! cache must be sure a file holds exactly what it wrote. The same format is
+//! used for artifacts compiled ahead of time, see
+//! [`crate::module::ModuleBuilder::compile_to_artifact`]. Each file starts
+//! with a header naming the format, the wasmer version, the target, the
+//! backend and the builder fingerprint that produced the module, followed by a
+//! digest over the header and the serialized module. The digest is an HMAC when the cache has a key, so
+//! that only holders of the key can produce files the cache will load.
+//!
+//! ```text
+//! magic (8) | format version (2) | wasmer version | target | backend | fingerprint | padding | digest (32) | module
+//! ```
+//!
+//! Strings are prefixed with their length as a little endian u16. The header
+//! is padded with zeros so that the module starts at a multiple of
+//! [`ALIGNMENT`] bytes, as wasmer can only deserialize aligned modules.
+
+use hmac::Hmac;
+use hmac::KeyInit;
+use hmac::Mac;
+use sha2::Digest;
+use sha2::Sha256;
+
+const MAGIC: &[u8; 8] = b"HCWASMER";
+
+/// Bumped whenever the layout of the file changes.
+const FORMAT_VERSION: u16 = 2;
+
+const DIGEST_LEN: usize = 32;
+
+const ALIGNMENT: usize = 16;
+
+/// What a serialized module must have been produced by to be loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ArtifactHeader {
+    pub(crate) wasmer_version: String,
+    pub(crate) target: String,
+    pub(crate) backend: String,
+    pub(crate) fingerprint: String,
+}
+
+impl ArtifactHeader {
+    /// The header of modules built in this process by the given backend and
+    /// a builder with the given [`crate::module::ModuleBuilder::fingerprint`].
+    pub(crate) fn new(backend: String, fingerprint: [u8; 32]) -> Self {
+        Self {
+            wasmer_version: wasmer_types::VERSION.to_string(),
+            target: wasmer_types::target::Triple::host().to_string(),
+            backend,
+            fingerprint: hex::encode(fingerprint),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        for field in [
+            &self.wasmer_version,
+            &self.target,
+            &self.backend,
+            &self.fingerprint,
+        ] {
+            // Nothing we put in a header comes close to 64KiB.
+            bytes.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+        }
+        bytes.resize(padded_len(bytes.len()), 0);
+        bytes
+    }
+
+    /// The header at the start of an artifact, and its encoded length.
+    fn decode(artifact: &[u8]) -> Result<(Self, usize), String> {
+        let too_short = || "Artifact is too short for its header".to_string();
+        let prefix = artifact.get(..MAGIC.len() + 2).ok_or_else(too_short)?;
+        if prefix[..MAGIC.len()] != MAGIC[..] {
+            return Err("Artifact is not a serialized module".to_string());
+        }
+        let format_version = u16::from_le_bytes([prefix[MAGIC.len()], prefix[MAGIC.len() + 1]]);
+        if format_version != FORMAT_VERSION {
+            return Err(format!(
+                "Artifact has format version {format_version}, not {FORMAT_VERSION}"
+            ));
+        }
+        let mut offset = prefix.len();
+        let mut field = || {
+            let len = artifact.get(offset..offset + 2).ok_or_else(too_short)?;
+            let len = usize::from(u16::from_le_bytes([len[0], len[1]]));
+            let field = artifact
+                .get(offset + 2..offset + 2 + len)
+                .ok_or_else(too_short)?;
+            offset += 2 + len;
+            String::from_utf8(field.to_vec()).map_err(|e| e.to_string())
+        };
+        let header = Self {
+            wasmer_version: field()?,
+            target: field()?,
+            backend: field()?,
+            fingerprint: field()?,
+        };
+        Ok((header, padded_len(offset)))
+    }
+}
+
+/// The length of a header padded so that the module after it and the digest
+/// is aligned.
+fn padded_len(len: usize) -> usize {
+    (len + DIGEST_LEN).next_multiple_of(ALIGNMENT) - DIGEST_LEN
+}
+
+/// Prefix a serialized module with the header and digest.
+pub(crate) fn seal(header: &ArtifactHeader, key: Option<&[u8]>, module: &[u8]) -> Vec<u8> {
+    let header = header.encode();
+    let digest = digest(key, &header, module);
+    let mut artifact = Vec::with_capacity(header.len() + DIGEST_LEN + module.len());
+    artifact.extend_from_slice(&header);
+    artifact.extend_from_slice(&digest);
+    artifact.extend_from_slice(module);
+    artifact
+}
+
+/// The serialized module in an artifact written by [`seal`], as long as the
+/// artifact has the expected header and its digest checks out.
+pub(crate) fn open(
+    header: &ArtifactHeader,
+    key: Option<&[u8]>,
+    artifact: bytes::Bytes,
+) -> Result<bytes::Bytes, String> {
+    let (found, header_len) = ArtifactHeader::decode(&artifact)?;
+    if &found != header {
+        return Err(format!(
+            "Artifact was not built by wasmer {} for {} with {} and builder fingerprint {}",
+            header.wasmer_version, header.target, header.backend, header.fingerprint
+        ));
+    }
+    let Some(found_digest) = artifact.get(header_len..header_len + DIGEST_LEN) else {
+        return Err("Artifact is too short for its digest".to_string());
+    };
+    let module = artifact.slice(header_len + DIGEST_LEN..);
+    if !verify(key, &artifact[..header_len], &module, found_digest) {
+        return Err("Artifact digest does not match its contents".to_string());
+    }
+    Ok(module)
+}
+
+fn digest(key: Option<&[u8]>, header: &[u8], module: &[u8]) -> [u8; DIGEST_LEN] {
+    match key {
+        Some(key) => {
+            let mut mac = hmac(key);
+            mac.update(header);
+            mac.update(module);
+            mac.finalize().into_bytes().into()
+        }
+        None => {
+            let mut hasher = Sha256::new();
+            hasher.update(header);
+            hasher.update(module);
+            hasher.finalize().into()
+        }
+    }
+}
+
+fn verify(key: Option<&[u8]>, header: &[u8], module: &[u8], found: &[u8]) -> bool {
+    match key {
+        // Compare in constant time so the digest can't be guessed byte by byte.
+        Some(key) => {
+            let mut mac = hmac(key);
+            mac.update(header);
+            mac.update(module);
+            mac.verify_slice(found).is_ok()
+        }
+        None => digest(None, header, module) == found,
+    }
+}
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    <Hmac<Sha256> as KeyInit>::new_from_slice(key).expect("HMAC takes keys of any size")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::open;
+    use super::seal;
+    use super::ArtifactHeader;
+    use bytes::Bytes;
+
+    #[test]
+    fn seal_open() {
+        let header = ArtifactHeader::new("cranelift".to_string(), [0; 32]);
+        for key in [None, Some(&b"secret"[..])] {
+            let artifact = seal(&header, key, b"module");
+            assert_eq!(
+                Ok(Bytes::from_static(b"module")),
+                open(&header, key, Bytes::from(artifact.clone()))
+            );
+
+            // Tampering with the module is detected.
+            let mut tampered = artifact.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(open(&header, key, Bytes::from(tampered)).is_err());
+
+            // So is a module built by something else.
+            for other in [
+                ArtifactHeader::new("llvm".to_string(), [0; 32]),
+                ArtifactHeader::new("cranelift".to_string(), [1; 32]),
+                ArtifactHeader {
+                    wasmer_version: "0.0.0".to_string(),
+                    ..header.clone()
+                },
+                ArtifactHeader {
+                    target: "wasm32-unknown-unknown".to_string(),
+                    ..header.clone()
+                },
+            ] {
+                assert!(open(&other, key, Bytes::from(artifact.clone())).is_err());
+            }
+
+            assert!(open(&header, Some(b"other"), Bytes::from(artifact.clone())).is_err());
+            assert!(open(&header, key, Bytes::from(artifact[..10].to_vec())).is_err());
+        }
+
+        // A keyed artifact doesn't pass for an unkeyed one either.
+        let artifact = seal(&header, Some(b"secret"), b"module");
+        assert!(open(&header, None, Bytes::from(artifact)).is_err());
+    }
+}
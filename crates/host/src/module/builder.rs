@@ -1,8 +1,14 @@
 use crate::prelude::*;
 use bytes::Bytes;
+use parking_lot::Mutex;
+use std::borrow::Cow;
 use std::sync::Arc;
+use std::sync::OnceLock;
 use wasmer::{Engine, Module};
 
+/// Rewrites wasm before a [`ModuleBuilder`] builds it.
+pub type Instrument = fn(&[u8]) -> Result<Vec<u8>, wasmer::RuntimeError>;
+
 /// Responsible for storing the wasmer Engine used to build wasmer Modules.
 #[derive(Debug)]
 pub struct ModuleBuilder {
@@ -12,6 +18,23 @@ pub struct ModuleBuilder {
     // The runtime engine is used only to execute function calls on instances,
     // so it does not require a compiler.
     runtime_engine: Engine,
+
+    // Rewrites the wasm before it is built, see `Self::instrument`.
+    instrument: Option<Instrument>,
+
+    // Whether to rewrite the wasm to check for interrupts, see
+    // `Self::interruptible`.
+    interruptible: bool,
+
+    // Identifies the compiler of `make_engine` in serialized modules.
+    backend: String,
+
+    // Built on first use, see `Self::fingerprint`.
+    fingerprint: OnceLock<[u8; 32]>,
+
+    // Engines that compiled modules used as they are, which own the code of
+    // those modules, see `Self::from_binary_keeping_engine`.
+    kept_engines: Mutex<Vec<Engine>>,
 }
 
 impl ModuleBuilder {
@@ -33,9 +56,56 @@ impl ModuleBuilder {
         Self {
             make_engine,
             runtime_engine: make_runtime_engine(),
+            instrument: None,
+            interruptible: false,
+            backend: make_engine().deterministic_id(),
+            fingerprint: OnceLock::new(),
+            kept_engines: Mutex::new(Vec::new()),
         }
     }
 
+    /// The compiler, or interpreter, of the engines that build modules, e.g.
+    /// `cranelift` or `wasmi`.
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    /// A hash of how this builder builds modules, which differs between
+    /// builders whose serialized modules can't be used in place of each
+    /// other, e.g. with another compiler, metering cost function or wasmer
+    /// version. `ModuleCache` keeps serialized modules apart by fingerprint.
+    ///
+    /// The first call builds a small module to find out, so it takes about as
+    /// long as building any other small module.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        *self
+            .fingerprint
+            .get_or_init(|| super::fingerprint::fingerprint(self))
+    }
+
+    /// Rewrite wasm with `instrument` before building it.
+    ///
+    /// This is how engines that can't run middleware are given metering,
+    /// e.g. `ModuleBuilder::new(wasmi::make_engine, wasmi::make_runtime_engine)
+    /// .instrument(wasmi::meter)`. Serialized modules are not instrumented
+    /// again, so on backends that serialize to wasm (i.e. wasmi) the rewritten
+    /// wasm is what gets cached.
+    pub fn instrument(mut self, instrument: Instrument) -> Self {
+        self.instrument = Some(instrument);
+        self
+    }
+
+    /// Rewrite wasm with [`super::inject_interrupt`] before building it, after
+    /// any [`Self::instrument`], so that instances of its modules can be
+    /// called with [`crate::guest::call_with_deadline`].
+    ///
+    /// The checks cost a little time, and on the sys backend with metering a
+    /// few points, on every function call and loop iteration.
+    pub fn interruptible(mut self, interruptible: bool) -> Self {
+        self.interruptible = interruptible;
+        self
+    }
+
     /// Build a Module from raw wasm bytes.
     ///
     /// `wasmer::Module::from_binary` performs full WebAssembly spec
@@ -49,13 +119,77 @@ impl ModuleBuilder {
     /// Do not reach for `Module::from_binary_unchecked` — that is the
     /// explicit "skip validation" escape hatch and is only safe for wasm
     /// that has already been validated out-of-band.
+    ///
+    /// Each module is compiled by a new engine, as middleware like metering
+    /// can only be used for one module. On the sys backend the compiled code
+    /// belongs to that engine, which is dropped on return, so the module is
+    /// only good for serializing. See [`Self::from_binary_keeping_engine`]
+    /// for a module that can be instantiated.
     pub fn from_binary(&self, wasm: &[u8]) -> Result<Arc<Module>, wasmer::RuntimeError> {
+        self.compile(wasm).map(|(module, _)| module)
+    }
+
+    /// Same as [`Self::from_binary`] but the engine that compiled the module
+    /// is kept for as long as the builder, so that the module can be
+    /// instantiated as it is rather than serialized and deserialized against
+    /// the runtime engine first.
+    ///
+    /// This is what `ModuleCache` does when it doesn't keep serialized
+    /// modules. The engine owns the code of the module, and instances of the
+    /// module run that code without holding on to the engine, so the engine
+    /// can't be dropped any sooner. Instances must not outlive the builder,
+    /// just like instances of modules from [`Self::from_serialized_module`],
+    /// whose code belongs to the runtime engine.
+    ///
+    /// Neither engine ever frees code before it is dropped, so the code of
+    /// every module built by the builder stays in memory either way. Keeping
+    /// the engine only adds the engine itself on top.
+    pub fn from_binary_keeping_engine(
+        &self,
+        wasm: &[u8],
+    ) -> Result<Arc<Module>, wasmer::RuntimeError> {
+        let (module, compiler_engine) = self.compile(wasm)?;
+        self.kept_engines.lock().push(compiler_engine);
+        Ok(module)
+    }
+
+    /// Compile a module with a new engine, see [`Self::from_binary`].
+    fn compile(&self, wasm: &[u8]) -> Result<(Arc<Module>, Engine), wasmer::RuntimeError> {
+        let mut wasm = Cow::Borrowed(wasm);
+        if let Some(instrument) = self.instrument {
+            wasm = Cow::Owned(instrument(&wasm)?);
+        }
+        if self.interruptible {
+            wasm = Cow::Owned(super::inject_interrupt(&wasm)?);
+        }
         let compiler_engine = (self.make_engine)();
         let module = Arc::new(
-            Module::from_binary(&compiler_engine, wasm)
+            Module::from_binary(&compiler_engine, &wasm)
                 .map_err(|e| wasm_error!(WasmErrorInner::ModuleBuild(e.to_string())))?,
         );
-        Ok(module)
+        Ok((module, compiler_engine))
+    }
+
+    /// Build a module and serialize it into an artifact that
+    /// `ModuleCache::import_artifact` can install, e.g. to compile wasm once
+    /// ahead of time and ship the result to hosts that don't have a compiler
+    /// or shouldn't spend the time.
+    ///
+    /// The artifact has the same header as the modules the cache serializes,
+    /// so it can only be imported by a cache whose builder has the same
+    /// backend and [`Self::fingerprint`], on the same wasmer version and
+    /// target. Its digest is unkeyed: it catches damage in transit but not
+    /// tampering, so artifacts must come from a trusted source.
+    pub fn compile_to_artifact(&self, wasm: &[u8]) -> Result<Bytes, wasmer::RuntimeError> {
+        let serialized_module = self
+            .from_binary(wasm)?
+            .serialize()
+            .map_err(|e| wasm_error!(WasmErrorInner::ModuleSerialize(e.to_string())))?;
+        Ok(Bytes::from(super::artifact::seal(
+            &super::artifact::ArtifactHeader::new(self.backend.clone(), self.fingerprint()),
+            None,
+            &serialized_module,
+        )))
     }
 
     /// Build a Module from a previously-serialized artifact.
@@ -70,15 +204,21 @@ impl ModuleBuilder {
     /// revalidation here — the wasm was already validated when the
     /// artifact was first built.
     ///
-    /// This function is only called from `ModuleCache::get` on the
-    /// filesystem-cache hit branch. The caller is therefore trusting
-    /// whatever lives at the cache path; the embedder is responsible for
-    /// protecting that directory from other writers. Corrupt or
-    /// version-mismatched files are handled by the cache: on deserialize
-    /// failure the file is evicted and the module is rebuilt from the
-    /// original wasm, which re-runs the validating path in
-    /// [`Self::from_binary`]. Tampering that still happens to produce a
-    /// deserializable artifact is *not* detected here.
+    /// This function is called from `ModuleCache::get` on the
+    /// filesystem-cache hit branch and from `ModuleCache::import_artifact`. Before that the cache checks the header
+    /// it writes in front of every serialized module: the module must have
+    /// been built by the same wasmer version, target, backend and builder
+    /// fingerprint, and must match the digest in the header. Corrupt, tampered or
+    /// version-mismatched files are handled by the cache: the file is
+    /// evicted and the module is rebuilt from the original wasm, which
+    /// re-runs the validating path in [`Self::from_binary`]. Imported
+    /// artifacts go through the same checks.
+    ///
+    /// A plain digest only catches accidents such as truncated files. Anyone
+    /// who can write to the cache directory can also compute the digest of
+    /// their own artifact, so unless the cache is given a key with
+    /// `ModuleCache::integrity_key`, the embedder is still responsible for
+    /// protecting that directory from other writers.
     pub fn from_serialized_module(
         &self,
         serialized_module: Bytes,
@@ -0,0 +1,166 @@
+use wasmer::wasmparser::Operator;
+
+/// Weights charged per wasm operator by metering, on either backend.
+///
+/// Operators are grouped into a handful of classes and every operator in a
+/// class costs the same number of points. Anything not covered by a more
+/// specific class (numeric instructions, constants, locals, globals, etc.) is
+/// charged as `arithmetic`.
+///
+/// Costs are charged once per operator executed, as determined at compile
+/// time. Bulk memory operators are charged a flat `bulk_memory` regardless of
+/// how many bytes they touch.
+///
+/// The cost model is baked into every module as it is built, so changing it
+/// requires rebuilding (and re-caching) modules.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CostModel {
+    /// Numeric instructions, constants, locals, globals and anything else not
+    /// covered by another class.
+    pub arithmetic: u64,
+    /// Loads, stores and `memory.size`.
+    pub memory: u64,
+    /// `memory.grow`, which may have to allocate and zero whole pages.
+    pub memory_grow: u64,
+    /// Blocks, branches and other structured control flow.
+    pub control_flow: u64,
+    /// Direct, indirect and tail calls.
+    pub call: u64,
+    /// `memory.copy`, `memory.fill`, `memory.init` and the table equivalents.
+    pub bulk_memory: u64,
+}
+
+impl CostModel {
+    /// Charge 1 point for every operator.
+    ///
+    /// This makes the metering limit an operator count. It is what every
+    /// engine factory that doesn't take a cost model meters with.
+    pub const fn flat() -> Self {
+        Self {
+            arithmetic: 1,
+            memory: 1,
+            memory_grow: 1,
+            control_flow: 1,
+            call: 1,
+            bulk_memory: 1,
+        }
+    }
+
+    /// The number of points charged for an operator.
+    pub fn cost(&self, operator: &Operator) -> u64 {
+        match operator {
+            Operator::I32Load { .. }
+            | Operator::I64Load { .. }
+            | Operator::F32Load { .. }
+            | Operator::F64Load { .. }
+            | Operator::I32Load8S { .. }
+            | Operator::I32Load8U { .. }
+            | Operator::I32Load16S { .. }
+            | Operator::I32Load16U { .. }
+            | Operator::I64Load8S { .. }
+            | Operator::I64Load8U { .. }
+            | Operator::I64Load16S { .. }
+            | Operator::I64Load16U { .. }
+            | Operator::I64Load32S { .. }
+            | Operator::I64Load32U { .. }
+            | Operator::I32Store { .. }
+            | Operator::I64Store { .. }
+            | Operator::F32Store { .. }
+            | Operator::F64Store { .. }
+            | Operator::I32Store8 { .. }
+            | Operator::I32Store16 { .. }
+            | Operator::I64Store8 { .. }
+            | Operator::I64Store16 { .. }
+            | Operator::I64Store32 { .. }
+            | Operator::MemorySize { .. } => self.memory,
+
+            Operator::MemoryGrow { .. } => self.memory_grow,
+
+            Operator::Unreachable
+            | Operator::Block { .. }
+            | Operator::Loop { .. }
+            | Operator::If { .. }
+            | Operator::Else
+            | Operator::End
+            | Operator::Br { .. }
+            | Operator::BrIf { .. }
+            | Operator::BrTable { .. }
+            | Operator::Return => self.control_flow,
+
+            Operator::Call { .. }
+            | Operator::CallIndirect { .. }
+            | Operator::ReturnCall { .. }
+            | Operator::ReturnCallIndirect { .. } => self.call,
+
+            Operator::MemoryInit { .. }
+            | Operator::MemoryCopy { .. }
+            | Operator::MemoryFill { .. }
+            | Operator::DataDrop { .. }
+            | Operator::TableInit { .. }
+            | Operator::TableCopy { .. }
+            | Operator::TableFill { .. }
+            | Operator::ElemDrop { .. } => self.bulk_memory,
+
+            _ => self.arithmetic,
+        }
+    }
+}
+
+impl Default for CostModel {
+    /// Roughly proportional to what each class costs natively.
+    ///
+    /// Arithmetic is the unit. Memory access adds a bounds check, calls set up
+    /// a new frame (and for indirect calls check the signature), and growing
+    /// memory or copying whole ranges of it is much more expensive than
+    /// anything else a guest can do in one operator.
+    fn default() -> Self {
+        Self {
+            arithmetic: 1,
+            memory: 2,
+            memory_grow: 1_000,
+            control_flow: 1,
+            call: 5,
+            bulk_memory: 50,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CostModel;
+    use wasmer::wasmparser::{MemArg, Operator};
+
+    #[test]
+    fn cost_by_class() {
+        let cost_model = CostModel {
+            arithmetic: 1,
+            memory: 2,
+            memory_grow: 3,
+            control_flow: 4,
+            call: 5,
+            bulk_memory: 6,
+        };
+        let memarg = MemArg {
+            align: 0,
+            max_align: 0,
+            offset: 0,
+            memory: 0,
+        };
+
+        assert_eq!(1, cost_model.cost(&Operator::I32Add));
+        assert_eq!(1, cost_model.cost(&Operator::LocalGet { local_index: 0 }));
+        assert_eq!(2, cost_model.cost(&Operator::I32Load { memarg }));
+        assert_eq!(3, cost_model.cost(&Operator::MemoryGrow { mem: 0 }));
+        assert_eq!(4, cost_model.cost(&Operator::Br { relative_depth: 0 }));
+        assert_eq!(5, cost_model.cost(&Operator::Call { function_index: 0 }));
+        assert_eq!(6, cost_model.cost(&Operator::MemoryFill { mem: 0 }));
+    }
+
+    #[test]
+    fn flat() {
+        let cost_model = CostModel::flat();
+        assert_eq!(1, cost_model.cost(&Operator::I32Add));
+        assert_eq!(1, cost_model.cost(&Operator::MemoryGrow { mem: 0 }));
+        assert_eq!(1, cost_model.cost(&Operator::Call { function_index: 0 }));
+    }
+}
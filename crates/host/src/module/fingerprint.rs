@@ -0,0 +1,177 @@
+use crate::module::ModuleBuilder;
+use crate::prelude::*;
+use sha2::Digest;
+use sha2::Sha256;
+use std::borrow::Cow;
+use wasm_encoder::BlockType;
+use wasm_encoder::CodeSection;
+use wasm_encoder::ConstExpr;
+use wasm_encoder::ElementSection;
+use wasm_encoder::Elements;
+use wasm_encoder::ExportKind;
+use wasm_encoder::ExportSection;
+use wasm_encoder::Function;
+use wasm_encoder::FunctionSection;
+use wasm_encoder::Instruction;
+use wasm_encoder::MemArg;
+use wasm_encoder::MemorySection;
+use wasm_encoder::MemoryType;
+use wasm_encoder::RefType;
+use wasm_encoder::TableSection;
+use wasm_encoder::TableType;
+use wasm_encoder::TypeSection;
+use wasm_encoder::ValType;
+
+/// Hash everything about how `builder` builds modules that ends up in the
+/// modules it serializes.
+///
+/// Engines don't tell us how they are configured, so rather than asking we
+/// build a small canary module and hash what comes out, along with the wasmer
+/// version, target and backend. Middleware such as metering is compiled into
+/// the canary just like into any other module, so changing the cost function
+/// or the limit changes the fingerprint too. A cost function that only
+/// differs for operators the canary doesn't use goes unnoticed, as does any
+/// engine configuration that only affects such operators.
+pub(crate) fn fingerprint(builder: &ModuleBuilder) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for field in [
+        wasmer_types::VERSION,
+        &wasmer_types::target::Triple::host().to_string(),
+        builder.backend(),
+    ] {
+        hasher.update((field.len() as u64).to_le_bytes());
+        hasher.update(field);
+    }
+    match builder.from_binary(&canary()).and_then(|module| {
+        module
+            .serialize()
+            .map_err(|e| wasm_error!(WasmErrorInner::ModuleSerialize(e.to_string())).into())
+    }) {
+        Ok(serialized) => hasher.update(serialized),
+        Err(e) => tracing::warn!(
+            "Failed to build canary module, the engine fingerprint only covers the backend: {:?}",
+            e
+        ),
+    }
+    hasher.finalize().into()
+}
+
+/// A module using a mix of the most common operators, and at least one of
+/// every [`super::CostModel`] class: locals, arithmetic, memory access,
+/// `memory.grow`, branches, direct and indirect calls and bulk memory.
+fn canary() -> Vec<u8> {
+    let mut types = TypeSection::new();
+    types.ty().function([ValType::I32], [ValType::I32]);
+
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    functions.function(0);
+    functions.function(0);
+
+    let mut tables = TableSection::new();
+    tables.table(TableType {
+        element_type: RefType::FUNCREF,
+        table64: false,
+        minimum: 1,
+        maximum: Some(1),
+        shared: false,
+    });
+
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: 1,
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+
+    let mut exports = ExportSection::new();
+    exports.export("canary", ExportKind::Func, 0);
+
+    let mut elements = ElementSection::new();
+    elements.active(
+        Some(0),
+        &ConstExpr::i32_const(0),
+        Elements::Functions(Cow::Borrowed(&[1])),
+    );
+
+    let mem_arg = MemArg {
+        offset: 0,
+        align: 2,
+        memory_index: 0,
+    };
+    let mut code = CodeSection::new();
+    let mut countdown = Function::new([]);
+    for instruction in [
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(0),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::LocalTee(0),
+        Instruction::LocalGet(0),
+        Instruction::I32Store(mem_arg),
+        Instruction::LocalGet(0),
+        Instruction::BrIf(0),
+        Instruction::End,
+        Instruction::LocalGet(0),
+        Instruction::Call(2),
+        Instruction::End,
+    ] {
+        countdown.instruction(&instruction);
+    }
+    code.function(&countdown);
+    let mut triple = Function::new([]);
+    for instruction in [
+        Instruction::LocalGet(0),
+        Instruction::I32Load(mem_arg),
+        Instruction::I64ExtendI32U,
+        Instruction::I64Const(3),
+        Instruction::I64Mul,
+        Instruction::I32WrapI64,
+        Instruction::End,
+    ] {
+        triple.instruction(&instruction);
+    }
+    code.function(&triple);
+    let mut grow = Function::new([]);
+    for instruction in [
+        Instruction::LocalGet(0),
+        Instruction::I32Const(0),
+        Instruction::I32Const(4),
+        Instruction::MemoryCopy {
+            src_mem: 0,
+            dst_mem: 0,
+        },
+        Instruction::LocalGet(0),
+        Instruction::I32Const(0),
+        Instruction::I32Const(4),
+        Instruction::MemoryFill(0),
+        Instruction::I32Const(1),
+        Instruction::MemoryGrow(0),
+        Instruction::Drop,
+        Instruction::MemorySize(0),
+        Instruction::LocalGet(0),
+        Instruction::I32Const(0),
+        Instruction::CallIndirect {
+            type_index: 0,
+            table_index: 0,
+        },
+        Instruction::I32Add,
+        Instruction::End,
+    ] {
+        grow.instruction(&instruction);
+    }
+    code.function(&grow);
+
+    let mut module = wasm_encoder::Module::new();
+    module
+        .section(&types)
+        .section(&functions)
+        .section(&tables)
+        .section(&memories)
+        .section(&exports)
+        .section(&elements)
+        .section(&code);
+    module.finish()
+}
@@ -0,0 +1,207 @@
+use std::ops::Range;
+use wasm_encoder::ConstExpr;
+use wasm_encoder::Encode;
+use wasm_encoder::ExportKind;
+use wasm_encoder::GlobalType;
+use wasm_encoder::RawSection;
+use wasm_encoder::ValType;
+use wasmer::wasmparser::BinaryReader;
+use wasmer::wasmparser::BinaryReaderError;
+use wasmer::wasmparser::FunctionBody;
+use wasmer::wasmparser::Operator;
+use wasmer::wasmparser::Parser;
+use wasmer::wasmparser::Payload;
+use wasmer::wasmparser::TypeRef;
+use wasmer::wasmparser::Validator;
+
+// Section ids in the order they must appear in a module. Custom sections may
+// appear anywhere so they have no place here.
+const SECTION_ORDER: [u8; 13] = [1, 2, 3, 4, 5, 13, 6, 7, 8, 9, 12, 10, 11];
+pub(super) const MEMORY_SECTION: u8 = 5;
+const GLOBAL_SECTION: u8 = 6;
+const EXPORT_SECTION: u8 = 7;
+pub(super) const CODE_SECTION: u8 = 10;
+
+/// Check that `wasm` is a valid module before rewriting it.
+///
+/// The rewrites append globals after the existing ones, so an invalid module
+/// that uses a global index it doesn't have would be valid afterwards and get
+/// at the new globals, e.g. to give itself more metering points.
+pub(super) fn validate(wasm: &[u8]) -> Result<(), BinaryReaderError> {
+    Validator::new().validate_all(wasm).map(|_| ())
+}
+
+/// The number of globals in a module, imported or not.
+///
+/// Globals appended by [`Injector`] get the indexes from here on, so no
+/// existing global index changes.
+pub(super) fn global_count(wasm: &[u8]) -> Result<u32, BinaryReaderError> {
+    let mut global_count = 0;
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader.into_imports() {
+                    if let TypeRef::Global(_) = import?.ty {
+                        global_count += 1;
+                    }
+                }
+            }
+            Payload::GlobalSection(reader) => global_count += reader.count(),
+            _ => {}
+        }
+    }
+    Ok(global_count)
+}
+
+/// Assembles a rewritten module section by section, appending mutable globals
+/// and exports of them.
+pub(super) struct Injector<'a> {
+    wasm: &'a [u8],
+    pub(super) module: wasm_encoder::Module,
+    globals: Vec<(ValType, ConstExpr)>,
+    exports: Vec<(&'static str, u32)>,
+    globals_done: bool,
+    exports_done: bool,
+}
+
+impl<'a> Injector<'a> {
+    pub(super) fn new(
+        wasm: &'a [u8],
+        globals: Vec<(ValType, ConstExpr)>,
+        exports: Vec<(&'static str, u32)>,
+    ) -> Self {
+        Self {
+            wasm,
+            module: wasm_encoder::Module::new(),
+            globals,
+            exports,
+            globals_done: false,
+            exports_done: false,
+        }
+    }
+
+    /// Emit the global and export sections if the module doesn't have them
+    /// and they are due before the section with id `id`.
+    pub(super) fn before(&mut self, id: u8) {
+        if !self.globals_done && comes_after(id, GLOBAL_SECTION) {
+            self.globals(None).expect("no existing section to read");
+        }
+        if !self.exports_done && comes_after(id, EXPORT_SECTION) {
+            self.exports(None).expect("no existing section to read");
+        }
+    }
+
+    /// Copy a section of the original module as is, or rewrite it if it is
+    /// the global or export section.
+    pub(super) fn copy(&mut self, id: u8, range: Range<usize>) -> Result<(), BinaryReaderError> {
+        match id {
+            GLOBAL_SECTION => self.globals(Some(range)),
+            EXPORT_SECTION => self.exports(Some(range)),
+            _ => {
+                self.before(id);
+                self.module.section(&RawSection {
+                    id,
+                    data: &self.wasm[range],
+                });
+                Ok(())
+            }
+        }
+    }
+
+    pub(super) fn finish(mut self) -> Vec<u8> {
+        self.before(u8::MAX);
+        self.module.finish()
+    }
+
+    /// Emit the global section with the new globals appended.
+    fn globals(&mut self, existing: Option<Range<usize>>) -> Result<(), BinaryReaderError> {
+        let (count, mut data) = self.items(existing)?;
+        for (val_type, init) in &self.globals {
+            GlobalType {
+                val_type: *val_type,
+                mutable: true,
+                shared: false,
+            }
+            .encode(&mut data);
+            init.encode(&mut data);
+        }
+        self.section(GLOBAL_SECTION, count + self.globals.len() as u32, &data);
+        self.globals_done = true;
+        Ok(())
+    }
+
+    /// Emit the export section with the new exports appended.
+    fn exports(&mut self, existing: Option<Range<usize>>) -> Result<(), BinaryReaderError> {
+        self.before(EXPORT_SECTION);
+        let (count, mut data) = self.items(existing)?;
+        for (name, index) in &self.exports {
+            name.encode(&mut data);
+            ExportKind::Global.encode(&mut data);
+            index.encode(&mut data);
+        }
+        self.section(EXPORT_SECTION, count + self.exports.len() as u32, &data);
+        self.exports_done = true;
+        Ok(())
+    }
+
+    /// The item count and encoded items of an existing section, if any.
+    fn items(&self, existing: Option<Range<usize>>) -> Result<(u32, Vec<u8>), BinaryReaderError> {
+        match existing {
+            Some(range) => {
+                let mut reader = BinaryReader::new(&self.wasm[range.clone()], range.start);
+                let count = reader.read_var_u32()?;
+                Ok((
+                    count,
+                    self.wasm[reader.original_position()..range.end].to_vec(),
+                ))
+            }
+            None => Ok((0, Vec::new())),
+        }
+    }
+
+    fn section(&mut self, id: u8, count: u32, items: &[u8]) {
+        let mut data = Vec::new();
+        count.encode(&mut data);
+        data.extend_from_slice(items);
+        self.module.section(&RawSection { id, data: &data });
+    }
+}
+
+/// Whether a section with id `id` belongs after a section with id `other`.
+pub(super) fn comes_after(id: u8, other: u8) -> bool {
+    let position = |id| SECTION_ORDER.iter().position(|&i| i == id);
+    match (position(id), position(other)) {
+        (Some(id), Some(other)) => id > other,
+        // Custom sections can go anywhere and `u8::MAX` marks the end.
+        _ => id == u8::MAX,
+    }
+}
+
+/// Copy a function body operator by operator.
+///
+/// `f` gets each operator along with its original encoding, and writes
+/// whatever should replace it.
+pub(super) fn rewrite_body(
+    body: &FunctionBody,
+    mut f: impl FnMut(&Operator, &[u8], &mut Vec<u8>),
+) -> Result<Vec<u8>, BinaryReaderError> {
+    let body_reader = body.get_binary_reader();
+    let wasm = body_reader
+        .clone()
+        .read_bytes(body_reader.bytes_remaining())?;
+    let start = body_reader.original_position();
+
+    let mut operators = body.get_operators_reader()?;
+    // Locals are copied verbatim.
+    let mut rewritten = wasm[..operators.original_position() - start].to_vec();
+    while !operators.eof() {
+        let (operator, offset) = operators.read_with_offset()?;
+        let end = operators.original_position();
+        f(
+            &operator,
+            &wasm[offset - start..end - start],
+            &mut rewritten,
+        );
+    }
+    Ok(rewritten)
+}
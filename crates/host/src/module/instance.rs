@@ -0,0 +1,205 @@
+use crate::module::is_interruptible;
+use crate::module::InstanceWithStore;
+#[cfg(feature = "async")]
+use crate::module::InstanceWithStoreAsync;
+use crate::module::CHECK_IMPORT;
+use crate::prelude::*;
+use std::sync::Arc;
+use wasmer::AsStoreMut;
+use wasmer::Engine;
+use wasmer::Function;
+use wasmer::FunctionEnv;
+use wasmer::Imports;
+use wasmer::Instance;
+use wasmer::Module;
+use wasmer::Store;
+use wasmer::StoreMut;
+
+/// Builds an [`InstanceWithStore`] from a [`Module`] and wires up its [`Env`].
+///
+/// Every instance the host calls into needs the same plumbing: a fresh
+/// [`Store`], a [`FunctionEnv<Env>`] to hand to host functions, the imports
+/// themselves, and then an [`Env`] populated from the instance exports so
+/// that [`Env::move_data_to_guest`] and friends can reach the guest memory
+/// and allocator. This builder does all of that in one place.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(feature = "wasmer-sys-cranelift")]
+/// # fn main() {
+/// use holochain_wasmer_host::module::{sys, InstanceBuilder, ModuleCache};
+/// use wasmer::Imports;
+///
+/// let cache = ModuleCache::new(sys::make_cranelift_engine, sys::make_runtime_engine, None);
+/// let wasm = wasmer::wat2wasm(
+///     br#"(module
+///         (memory (export "memory") 1)
+///         (func (export "__hc__allocate_1") (param i32) (result i32) i32.const 0)
+///         (func (export "__hc__deallocate_1") (param i32 i32)))"#,
+/// )
+/// .unwrap();
+/// let module = cache.get([0u8; 32], &wasm).unwrap();
+///
+/// let instance_with_store = InstanceBuilder::new(module, sys::make_runtime_engine)
+///     .build(|_store, _function_env| Imports::new())
+///     .unwrap();
+/// # let _ = instance_with_store;
+/// # }
+/// # #[cfg(not(feature = "wasmer-sys-cranelift"))]
+/// # fn main() {}
+/// ```
+#[derive(Debug)]
+pub struct InstanceBuilder {
+    module: Arc<Module>,
+
+    // Creates the engine backing the store of the new instance.
+    //
+    // The sys backend can instantiate a module in a store of any sys engine,
+    // but the wasmi backend requires the store to share the engine that built
+    // the module, so the caller picks the backend here too.
+    make_store_engine: fn() -> Engine,
+
+    // Whether to look up the metering globals injected by the metering
+    // middleware and make them available through the `Env`.
+    metered: bool,
+}
+
+impl InstanceBuilder {
+    /// Construct an `InstanceBuilder` for a module.
+    ///
+    /// `make_store_engine` is invoked once per [`Self::build`] to create the
+    /// engine of the new instance's [`Store`]. Pass the same runtime engine
+    /// factory that was given to [`crate::module::ModuleBuilder::new`], e.g.
+    /// `sys::make_runtime_engine` or `wasmi::make_runtime_engine`.
+    ///
+    /// Metering is off by default, see [`Self::metered`].
+    pub fn new(module: Arc<Module>, make_store_engine: fn() -> Engine) -> Self {
+        Self {
+            module,
+            make_store_engine,
+            metered: false,
+        }
+    }
+
+    /// Require the metering globals to be exported by the instance and set
+    /// them on the [`Env`].
+    ///
+    /// Only modules compiled by an engine with the metering middleware (e.g.
+    /// `sys::make_cranelift_engine`) or instrumented with
+    /// [`crate::module::inject_metering`] (e.g. by `wasmi::meter`) export these
+    /// globals, so building a metered instance from any other module fails with
+    /// [`WasmErrorInner::MissingExport`].
+    pub fn metered(mut self, metered: bool) -> Self {
+        self.metered = metered;
+        self
+    }
+
+    /// Instantiate the module and return it along with its store.
+    ///
+    /// `imports` is called with the new store and the [`FunctionEnv<Env>`]
+    /// that host functions should be bound to. The [`Env`] is fully
+    /// initialised from the instance exports before this returns.
+    ///
+    /// The check that modules built with
+    /// [`crate::module::ModuleBuilder::interruptible`] import is added to the
+    /// imports unless they already have it.
+    pub fn build<F>(self, imports: F) -> Result<InstanceWithStore, wasmer::RuntimeError>
+    where
+        F: FnOnce(&mut StoreMut, &FunctionEnv<Env>) -> Imports,
+    {
+        let (store, instance) = self.instantiate(imports)?;
+        Ok(InstanceWithStore {
+            store: Arc::new(Mutex::new(store)),
+            instance: Arc::new(instance),
+        })
+    }
+
+    /// Same as [`Self::build`] but the store can be used to call the guest
+    /// with [`crate::guest::call_async`].
+    ///
+    /// Host functions can be async too, see [`Env::with_async`].
+    #[cfg(feature = "async")]
+    pub fn build_async<F>(self, imports: F) -> Result<InstanceWithStoreAsync, wasmer::RuntimeError>
+    where
+        F: FnOnce(&mut StoreMut, &FunctionEnv<Env>) -> Imports,
+    {
+        let (store, instance) = self.instantiate(imports)?;
+        Ok(InstanceWithStoreAsync {
+            store: store.into_async(),
+            instance: Arc::new(instance),
+        })
+    }
+
+    fn instantiate<F>(self, imports: F) -> Result<(Store, Instance), wasmer::RuntimeError>
+    where
+        F: FnOnce(&mut StoreMut, &FunctionEnv<Env>) -> Imports,
+    {
+        let mut store = Store::new((self.make_store_engine)());
+        let function_env;
+        let instance;
+        {
+            let mut store_mut = store.as_store_mut();
+            function_env = FunctionEnv::new(&mut store_mut, Env::default());
+            let mut built_imports = imports(&mut store_mut, &function_env);
+            let (namespace, name) = CHECK_IMPORT;
+            if is_interruptible(&self.module) && !built_imports.exists(namespace, name) {
+                built_imports.define(
+                    namespace,
+                    name,
+                    Function::new_typed(&mut store_mut, crate::guest::interrupt::check),
+                );
+            }
+            instance = Instance::new(&mut store_mut, &self.module, &built_imports)
+                .map_err(|e| wasm_error!(WasmErrorInner::Instantiate(e.to_string())))?;
+        }
+
+        {
+            let mut function_env_mut = function_env.into_mut(&mut store);
+            let (env, store_mut) = function_env_mut.data_and_store_mut();
+            env.memory = Some(
+                instance
+                    .exports
+                    .get_memory("memory")
+                    .map_err(|_| missing_export("memory"))?
+                    .clone(),
+            );
+            env.allocate = Some(
+                instance
+                    .exports
+                    .get_typed_function(&store_mut, "__hc__allocate_1")
+                    .map_err(|_| missing_export("__hc__allocate_1"))?,
+            );
+            env.deallocate = Some(
+                instance
+                    .exports
+                    .get_typed_function(&store_mut, "__hc__deallocate_1")
+                    .map_err(|_| missing_export("__hc__deallocate_1"))?,
+            );
+            if self.metered {
+                env.wasmer_metering_points_exhausted = Some(
+                    instance
+                        .exports
+                        .get_global("wasmer_metering_points_exhausted")
+                        .map_err(|_| missing_export("wasmer_metering_points_exhausted"))?
+                        .clone(),
+                );
+                env.wasmer_metering_remaining_points = Some(
+                    instance
+                        .exports
+                        .get_global("wasmer_metering_remaining_points")
+                        .map_err(|_| missing_export("wasmer_metering_remaining_points"))?
+                        .clone(),
+                );
+            }
+        }
+
+        Ok((store, instance))
+    }
+}
+
+/// An export that exists with the wrong type is no more usable than one that
+/// doesn't exist at all, so both are reported as missing.
+fn missing_export(name: &str) -> wasmer::RuntimeError {
+    wasm_error!(WasmErrorInner::MissingExport(name.to_string())).into()
+}
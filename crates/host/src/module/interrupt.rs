@@ -0,0 +1,387 @@
+use crate::module::inject::comes_after;
+use crate::module::inject::validate;
+use crate::prelude::*;
+use std::convert::Infallible;
+use wasm_encoder::reencode::Error;
+use wasm_encoder::reencode::Reencode;
+use wasm_encoder::BlockType;
+use wasm_encoder::CodeSection;
+use wasm_encoder::ConstExpr;
+use wasm_encoder::EntityType;
+use wasm_encoder::Function;
+use wasm_encoder::GlobalSection;
+use wasm_encoder::GlobalType;
+use wasm_encoder::ImportSection;
+use wasm_encoder::Instruction;
+use wasm_encoder::SectionId;
+use wasm_encoder::TypeSection;
+use wasm_encoder::ValType;
+use wasmer::Module;
+use wasmparser::FunctionBody;
+use wasmparser::GlobalSectionReader;
+use wasmparser::ImportSectionReader;
+use wasmparser::Parser;
+use wasmparser::Payload;
+use wasmparser::TypeRef;
+use wasmparser::TypeSectionReader;
+
+/// The host function that interruptible modules import to find out whether
+/// to stop, as `(namespace, name)`.
+pub(crate) const CHECK_IMPORT: (&str, &str) = ("env", "__hc__interrupt_1");
+
+/// Whether instances of `module` can be interrupted, i.e. it was rewritten
+/// by [`inject_interrupt`].
+pub(crate) fn is_interruptible(module: &Module) -> bool {
+    module
+        .imports()
+        .any(|import| (import.module(), import.name()) == CHECK_IMPORT)
+}
+
+/// Rewrite a wasm module to stop when the host asks it to, so that
+/// [`crate::guest::call_with_deadline`] can halt it at the deadline on any
+/// backend.
+///
+/// wasmer can't interrupt a running guest, and the guest's state can only be
+/// touched safely from the thread running it, so the guest has to ask
+/// instead. The rewritten module counts function calls and loop iterations,
+/// and every so many of them calls a host function that it imports as
+/// `env.__hc__interrupt_1`. The host function traps once the deadline has
+/// passed, and otherwise tells the guest how long to go until it asks again.
+/// [`crate::module::InstanceBuilder`] provides the import.
+///
+/// The import goes after any existing imports, so every function defined by
+/// the module moves up one index, and a mutable global to count with goes
+/// after any existing globals.
+///
+/// Fails with [`WasmErrorInner::ModuleBuild`] if `wasm` isn't valid.
+pub fn inject_interrupt(wasm: &[u8]) -> Result<Vec<u8>, wasmer::RuntimeError> {
+    validate(wasm).map_err(module_build)?;
+    let mut interrupter = Interrupter::new(wasm).map_err(module_build)?;
+    let mut module = wasm_encoder::Module::new();
+    interrupter
+        .parse_core_module(&mut module, Parser::new(0), wasm)
+        .map_err(module_build)?;
+    Ok(module.finish())
+}
+
+fn module_build(e: impl std::fmt::Display) -> wasmer::RuntimeError {
+    wasm_error!(WasmErrorInner::ModuleBuild(e.to_string())).into()
+}
+
+/// Copies a module while adding the check import, its type and the counting
+/// global, each at the end of its section, adding the section if the module
+/// doesn't have it.
+struct Interrupter {
+    imported_functions: u32,
+    check_type: u32,
+    ticks: u32,
+    types_done: bool,
+    imports_done: bool,
+    globals_done: bool,
+}
+
+impl Interrupter {
+    fn new(wasm: &[u8]) -> Result<Self, wasmparser::BinaryReaderError> {
+        let mut types = 0;
+        let mut imported_functions = 0;
+        let mut globals = 0;
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload? {
+                Payload::TypeSection(reader) => {
+                    for rec_group in reader {
+                        types += rec_group?.types().len() as u32;
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader.into_imports() {
+                        match import?.ty {
+                            TypeRef::Func(_) | TypeRef::FuncExact(_) => imported_functions += 1,
+                            TypeRef::Global(_) => globals += 1,
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::GlobalSection(reader) => globals += reader.count(),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            imported_functions,
+            check_type: types,
+            ticks: globals,
+            types_done: false,
+            imports_done: false,
+            globals_done: false,
+        })
+    }
+
+    /// The check import takes the index of the first defined function.
+    fn check_function(&self) -> u32 {
+        self.imported_functions
+    }
+
+    fn add_type(&mut self, types: &mut TypeSection) {
+        types.ty().function([], [ValType::I32]);
+        self.types_done = true;
+    }
+
+    fn add_import(&mut self, imports: &mut ImportSection) {
+        imports.import(
+            CHECK_IMPORT.0,
+            CHECK_IMPORT.1,
+            EntityType::Function(self.check_type),
+        );
+        self.imports_done = true;
+    }
+
+    fn add_global(&mut self, globals: &mut GlobalSection) {
+        // Zero so the guest checks as soon as it starts.
+        globals.global(
+            GlobalType {
+                val_type: ValType::I32,
+                mutable: true,
+                shared: false,
+            },
+            &ConstExpr::i32_const(0),
+        );
+        self.globals_done = true;
+    }
+
+    /// Count down the ticks, and once they run out call the check, which
+    /// traps or returns the ticks until the next check.
+    fn check(&self, function: &mut Function) {
+        for instruction in [
+            Instruction::GlobalGet(self.ticks),
+            Instruction::If(BlockType::Empty),
+            Instruction::GlobalGet(self.ticks),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::GlobalSet(self.ticks),
+            Instruction::Else,
+            Instruction::Call(self.check_function()),
+            Instruction::GlobalSet(self.ticks),
+            Instruction::End,
+        ] {
+            function.instruction(&instruction);
+        }
+    }
+}
+
+impl Reencode for Interrupter {
+    type Error = Infallible;
+
+    fn function_index(&mut self, func: u32) -> Result<u32, Error> {
+        Ok(if func < self.imported_functions {
+            func
+        } else {
+            func + 1
+        })
+    }
+
+    fn parse_type_section(
+        &mut self,
+        types: &mut TypeSection,
+        section: TypeSectionReader<'_>,
+    ) -> Result<(), Error> {
+        wasm_encoder::reencode::utils::parse_type_section(self, types, section)?;
+        self.add_type(types);
+        Ok(())
+    }
+
+    fn parse_import_section(
+        &mut self,
+        imports: &mut ImportSection,
+        section: ImportSectionReader<'_>,
+    ) -> Result<(), Error> {
+        wasm_encoder::reencode::utils::parse_import_section(self, imports, section)?;
+        self.add_import(imports);
+        Ok(())
+    }
+
+    fn parse_global_section(
+        &mut self,
+        globals: &mut GlobalSection,
+        section: GlobalSectionReader<'_>,
+    ) -> Result<(), Error> {
+        wasm_encoder::reencode::utils::parse_global_section(self, globals, section)?;
+        self.add_global(globals);
+        Ok(())
+    }
+
+    /// Check on entry to every function and at the start of every loop
+    /// iteration, which is every way for a guest to keep running.
+    fn parse_function_body(
+        &mut self,
+        code: &mut CodeSection,
+        func: FunctionBody<'_>,
+    ) -> Result<(), Error> {
+        let mut function = self.new_function_with_parsed_locals(&func)?;
+        self.check(&mut function);
+        let mut reader = func.get_operators_reader()?;
+        while !reader.eof() {
+            let instruction = self.parse_instruction(&mut reader)?;
+            function.instruction(&instruction);
+            if let Instruction::Loop(_) = instruction {
+                self.check(&mut function);
+            }
+        }
+        code.function(&function);
+        Ok(())
+    }
+
+    /// Add the sections the module doesn't have once they are due.
+    fn intersperse_section_hook(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        _after: Option<SectionId>,
+        before: Option<SectionId>,
+    ) -> Result<(), Error> {
+        let due = |section: SectionId| match before {
+            Some(before) => comes_after(before as u8, section as u8),
+            None => true,
+        };
+        if !self.types_done && due(SectionId::Type) {
+            let mut types = TypeSection::new();
+            self.add_type(&mut types);
+            module.section(&types);
+        }
+        if !self.imports_done && due(SectionId::Import) {
+            let mut imports = ImportSection::new();
+            self.add_import(&mut imports);
+            module.section(&imports);
+        }
+        if !self.globals_done && due(SectionId::Global) {
+            let mut globals = GlobalSection::new();
+            self.add_global(&mut globals);
+            module.section(&globals);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "wasmer-sys-cranelift"))]
+mod tests {
+    use super::inject_interrupt;
+    use crate::prelude::*;
+    use wasmer::imports;
+    use wasmer::Engine;
+    use wasmer::Function;
+    use wasmer::FunctionEnv;
+    use wasmer::FunctionEnvMut;
+    use wasmer::Instance;
+    use wasmer::Module;
+    use wasmer::Store;
+    use wasmer::TypedFunction;
+    use wasmer::Value;
+
+    /// Instantiate the rewritten `wat` with a check that counts its calls and
+    /// asks to be called again after `interval` ticks.
+    fn instantiate(
+        wat: &[u8],
+        interval: i32,
+        imports: impl FnOnce(&mut Store) -> wasmer::Imports,
+    ) -> (Store, Instance, FunctionEnv<u32>) {
+        let wasm = inject_interrupt(&wasmer::wat2wasm(wat).unwrap()).unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm).unwrap();
+        let mut store = Store::new(engine);
+        let checks = FunctionEnv::new(&mut store, 0);
+        let check = Function::new_typed_with_env(
+            &mut store,
+            &checks,
+            move |mut checks: FunctionEnvMut<u32>| {
+                *checks.data_mut() += 1;
+                interval
+            },
+        );
+        let mut imports = imports(&mut store);
+        imports.define("env", "__hc__interrupt_1", check);
+        let instance = Instance::new(&mut store, &module, &imports).unwrap();
+        (store, instance, checks)
+    }
+
+    #[test]
+    fn keeps_function_indexes_working() {
+        let (mut store, instance, checks) = instantiate(
+            br#"(module
+                (import "env" "double" (func $double (param i32) (result i32)))
+                (table 1 funcref)
+                (elem (i32.const 0) $add_one)
+                (global $started (mut i32) (i32.const 0))
+                (func $add_one (param i32) (result i32)
+                    (i32.add (local.get 0) (i32.const 1)))
+                (func $start (global.set $started (i32.const 1)))
+                (start $start)
+                (func (export "f") (param i32) (result i32)
+                    (call_indirect (param i32) (result i32)
+                        (call $double (local.get 0))
+                        (i32.const 0)))
+                (export "started" (global $started)))"#,
+            1_000,
+            |store| {
+                imports! {
+                    "env" => {
+                        "double" => Function::new_typed(store, |x: i32| x * 2),
+                    }
+                }
+            },
+        );
+        let started = instance.exports.get_global("started").unwrap();
+        assert_eq!(Value::I32(1), started.get(&mut store));
+        let f: TypedFunction<i32, i32> = instance.exports.get_typed_function(&store, "f").unwrap();
+        assert_eq!(41, f.call(&mut store, 20).unwrap());
+        // Once when the start function ran, as the guest starts with no ticks.
+        assert_eq!(1, *checks.as_ref(&store));
+    }
+
+    #[test]
+    fn checks_in_loops() {
+        let (mut store, instance, checks) = instantiate(
+            br#"(module
+                (func (export "count") (param i32)
+                    (loop $l
+                        (local.set 0 (i32.sub (local.get 0) (i32.const 1)))
+                        (br_if $l (local.get 0)))))"#,
+            10,
+            |_| imports! {},
+        );
+        let count: TypedFunction<i32, ()> = instance
+            .exports
+            .get_typed_function(&store, "count")
+            .unwrap();
+        count.call(&mut store, 1_000).unwrap();
+        // Every 10 ticks, on top of the one on entry.
+        let checks = *checks.as_ref(&store);
+        assert!((90..=110).contains(&checks), "{checks} checks");
+    }
+
+    #[test]
+    fn adds_missing_sections() {
+        let (mut store, instance, checks) =
+            instantiate(br#"(module (func (export "f")))"#, 10, |_| imports! {});
+        instance
+            .exports
+            .get_function("f")
+            .unwrap()
+            .call(&mut store, &[])
+            .unwrap();
+        assert_eq!(1, *checks.as_ref(&store));
+    }
+
+    #[test]
+    fn rejects_invalid_wasm() {
+        // Sets the global the rewrite would add, if it got the chance.
+        let wasm =
+            wasmer::wat2wasm(br#"(module (func (export "f") (global.set 0 (i32.const 1000000))))"#)
+                .unwrap();
+        assert!(wasmer::wasmparser::validate(&wasm).is_err());
+        assert!(matches!(
+            inject_interrupt(&wasm)
+                .unwrap_err()
+                .downcast::<WasmError>()
+                .unwrap()
+                .error,
+            WasmErrorInner::ModuleBuild(_),
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+use crate::module::inject::global_count;
+use crate::module::inject::rewrite_body;
+use crate::module::inject::validate;
+use crate::module::inject::Injector;
+use crate::module::inject::CODE_SECTION;
+use crate::module::inject::MEMORY_SECTION;
+use crate::prelude::*;
+use wasm_encoder::ConstExpr;
+use wasm_encoder::Encode;
+use wasm_encoder::Instruction;
+use wasm_encoder::MemorySection;
+use wasm_encoder::MemoryType;
+use wasm_encoder::ValType;
+use wasmer::wasmparser::BinaryReaderError;
+use wasmer::wasmparser::FunctionBody;
+use wasmer::wasmparser::Operator;
+use wasmer::wasmparser::Parser;
+use wasmer::wasmparser::Payload;
+use wasmer::wasmparser::TypeRef;
+use wasmer::Instance;
+use wasmer::StoreMut;
+
+/// The global that counts failed attempts to grow memory in modules rewritten
+/// by [`inject_memory_limit`].
+const MEMORY_LIMIT_HITS_EXPORT: &str = "__hc__memory_limit_hits";
+
+/// Rewrite a wasm module so that none of the memories it defines can grow
+/// beyond `max_pages` wasm pages, for engines that can't limit memory when
+/// instantiating, such as the wasmi interpreter.
+///
+/// The limit becomes the declared maximum of each memory, or is left alone if
+/// the module already declares a lower one. `memory.grow` past the limit then
+/// fails in the guest exactly as it does with
+/// `sys::make_runtime_engine_with_memory_limit`, and calls that end this way
+/// fail with [`WasmErrorInner::MemoryLimit`].
+///
+/// The module counts its failed attempts to grow in an extra exported global.
+/// Inject metering first if the module is metered too, so that counting isn't
+/// charged for.
+///
+/// Fails with [`WasmErrorInner::MemoryLimit`] if a memory starts out bigger
+/// than the limit, and with [`WasmErrorInner::ModuleBuild`] if `wasm` isn't
+/// valid.
+pub fn inject_memory_limit(wasm: &[u8], max_pages: u32) -> Result<Vec<u8>, wasmer::RuntimeError> {
+    match inject(wasm, max_pages) {
+        Ok(Some(wasm)) => Ok(wasm),
+        Ok(None) => Err(wasm_error!(WasmErrorInner::MemoryLimit).into()),
+        Err(e) => Err(wasm_error!(WasmErrorInner::ModuleBuild(e.to_string())).into()),
+    }
+}
+
+/// How many times the memories of an instance failed to grow so far.
+///
+/// Only memories limited by `sys::make_runtime_engine_with_memory_limit` or
+/// [`inject_memory_limit`] are counted. The count belongs to the instance, so
+/// comparing it before and after a call tells whether the call ran into the
+/// limit, whichever thread the guest ran on.
+pub(crate) fn memory_limit_hits(store_mut: &mut StoreMut, instance: &Instance) -> u64 {
+    let injected = instance
+        .exports
+        .get_global(MEMORY_LIMIT_HITS_EXPORT)
+        .ok()
+        .and_then(|global| global.get(store_mut).i32())
+        .map_or(0, |hits| u64::from(hits as u32));
+    #[cfg(feature = "wasmer-sys")]
+    let injected = injected + crate::module::sys::memory_limit_hits(store_mut, instance);
+    injected
+}
+
+/// `None` if a memory doesn't fit within the limit.
+fn inject(wasm: &[u8], max_pages: u32) -> Result<Option<Vec<u8>>, BinaryReaderError> {
+    validate(wasm)?;
+    let max_pages = u64::from(max_pages);
+    let hits = global_count(wasm)?;
+    let scratch = hits + 1;
+    let memory64 = memory64(wasm)?;
+
+    let mut injector = Injector::new(
+        wasm,
+        vec![
+            (ValType::I32, ConstExpr::i32_const(0)),
+            (ValType::I32, ConstExpr::i32_const(0)),
+        ],
+        vec![(MEMORY_LIMIT_HITS_EXPORT, hits)],
+    );
+    let mut code_section = wasm_encoder::CodeSection::new();
+    let mut code_remaining = 0;
+    for payload in Parser::new(0).parse_all(wasm) {
+        let payload = payload?;
+        match &payload {
+            Payload::Version { .. } | Payload::End(_) => {}
+            Payload::MemorySection(reader) => {
+                let mut memories = MemorySection::new();
+                for memory in reader.clone() {
+                    let memory = memory?;
+                    if memory.initial > max_pages {
+                        return Ok(None);
+                    }
+                    memories.memory(MemoryType {
+                        minimum: memory.initial,
+                        maximum: Some(memory.maximum.map_or(max_pages, |m| m.min(max_pages))),
+                        memory64: memory.memory64,
+                        shared: memory.shared,
+                        page_size_log2: memory.page_size_log2,
+                    });
+                }
+                injector.before(MEMORY_SECTION);
+                injector.module.section(&memories);
+            }
+            Payload::CodeSectionStart { count, .. } => {
+                injector.before(CODE_SECTION);
+                code_remaining = *count;
+                if code_remaining == 0 {
+                    injector.module.section(&code_section);
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                code_section.raw(&instrument(body, &memory64, hits, scratch)?);
+                code_remaining -= 1;
+                if code_remaining == 0 {
+                    injector.module.section(&code_section);
+                }
+            }
+            _ => {
+                if let Some((id, range)) = payload.as_section() {
+                    injector.copy(id, range)?;
+                }
+            }
+        }
+    }
+    Ok(Some(injector.finish()))
+}
+
+/// Whether each memory of a module, imported or not, is 64 bit, by index.
+fn memory64(wasm: &[u8]) -> Result<Vec<bool>, BinaryReaderError> {
+    let mut memory64 = Vec::new();
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader.into_imports() {
+                    if let TypeRef::Memory(ty) = import?.ty {
+                        memory64.push(ty.memory64);
+                    }
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for memory in reader {
+                    memory64.push(memory?.memory64);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(memory64)
+}
+
+/// Copy a function body, counting every `memory.grow` of a 32 bit memory that
+/// fails.
+fn instrument(
+    body: &FunctionBody,
+    memory64: &[bool],
+    hits: u32,
+    scratch: u32,
+) -> Result<Vec<u8>, BinaryReaderError> {
+    rewrite_body(body, |operator, original, instrumented| {
+        instrumented.extend_from_slice(original);
+        if let Operator::MemoryGrow { mem } = operator {
+            if memory64.get(*mem as usize) == Some(&false) {
+                // hits += (result == -1), leaving the result on the stack.
+                for instruction in [
+                    Instruction::GlobalSet(scratch),
+                    Instruction::GlobalGet(hits),
+                    Instruction::GlobalGet(scratch),
+                    Instruction::I32Const(-1),
+                    Instruction::I32Eq,
+                    Instruction::I32Add,
+                    Instruction::GlobalSet(hits),
+                    Instruction::GlobalGet(scratch),
+                ] {
+                    instruction.encode(instrumented);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(all(test, feature = "wasmer-sys-cranelift"))]
+mod tests {
+    use super::inject_memory_limit;
+    use super::memory_limit_hits;
+    use crate::prelude::*;
+    use wasmer::imports;
+    use wasmer::AsStoreMut;
+    use wasmer::Engine;
+    use wasmer::Instance;
+    use wasmer::Module;
+    use wasmer::Pages;
+    use wasmer::Store;
+    use wasmer::Value;
+
+    #[test]
+    fn memory_limit() {
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (memory (export "memory") 1)
+                (func (export "grow") (param i32) (result i32)
+                    (memory.grow (local.get 0))))"#,
+        )
+        .unwrap();
+        let wasm = inject_memory_limit(&wasm, 3).unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm).unwrap();
+        let mut store = Store::new(engine.clone());
+        let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+        let memory = instance.exports.get_memory("memory").unwrap();
+        assert_eq!(Some(Pages(3)), memory.ty(&store).maximum);
+
+        let grow = instance.exports.get_function("grow").unwrap();
+        assert_eq!(
+            Value::I32(1),
+            grow.call(&mut store, &[Value::I32(2)]).unwrap()[0]
+        );
+        assert_eq!(0, memory_limit_hits(&mut store.as_store_mut(), &instance));
+        assert_eq!(
+            Value::I32(-1),
+            grow.call(&mut store, &[Value::I32(1)]).unwrap()[0]
+        );
+        assert_eq!(1, memory_limit_hits(&mut store.as_store_mut(), &instance));
+    }
+
+    #[test]
+    fn rejects_invalid_wasm() {
+        // Resets the count of failed grows, if it got the chance.
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (memory 1)
+                (func (export "f") (global.set 0 (i32.const 0))))"#,
+        )
+        .unwrap();
+        assert!(wasmer::wasmparser::validate(&wasm).is_err());
+        assert!(matches!(
+            inject_memory_limit(&wasm, 3)
+                .unwrap_err()
+                .downcast::<WasmError>()
+                .unwrap()
+                .error,
+            WasmErrorInner::ModuleBuild(_),
+        ));
+    }
+
+    #[test]
+    fn memory_over_limit() {
+        let wasm = wasmer::wat2wasm(br#"(module (memory 4))"#).unwrap();
+        assert_eq!(
+            WasmErrorInner::MemoryLimit,
+            inject_memory_limit(&wasm, 3)
+                .unwrap_err()
+                .downcast::<WasmError>()
+                .unwrap()
+                .error,
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use crate::module::inject::global_count;
+use crate::module::inject::rewrite_body;
+use crate::module::inject::validate;
+use crate::module::inject::Injector;
+use crate::module::inject::CODE_SECTION;
+use crate::module::CostModel;
+use crate::prelude::*;
+use wasm_encoder::BlockType;
+use wasm_encoder::ConstExpr;
+use wasm_encoder::Encode;
+use wasm_encoder::Instruction;
+use wasm_encoder::ValType;
+use wasmer::wasmparser::BinaryReaderError;
+use wasmer::wasmparser::FunctionBody;
+use wasmer::wasmparser::Operator;
+use wasmer::wasmparser::Parser;
+use wasmer::wasmparser::Payload;
+
+#[cfg(not(test))]
+/// one hundred giga ops
+///
+/// This is only the default that instances start with. Use the
+/// `*_with_metering_limit` engine factories to pick a different initial limit
+/// and [`crate::guest::set_remaining_points`] to set a budget per call.
+pub const WASM_METERING_LIMIT: u64 = 100_000_000_000;
+
+#[cfg(test)]
+/// ten mega ops.
+/// We don't want tests to run forever, and it can take several minutes for 100 giga ops to run.
+pub const WASM_METERING_LIMIT: u64 = 10_000_000;
+
+const REMAINING_POINTS_EXPORT: &str = "wasmer_metering_remaining_points";
+const POINTS_EXHAUSTED_EXPORT: &str = "wasmer_metering_points_exhausted";
+
+/// Rewrite a wasm module to meter itself, for engines that can't add metering
+/// while compiling, such as the wasmi interpreter.
+///
+/// The result behaves exactly like a module compiled with the
+/// `wasmer_middlewares` metering middleware: it exports the same two globals,
+/// checks and charges points at the same places and traps with `unreachable`
+/// once the points run out. Everything that reads or writes the points of a
+/// sys instance, e.g. [`crate::guest::set_remaining_points`], therefore works
+/// unchanged on instances of the rewritten module.
+///
+/// Instances start with `metering_limit` points. Don't inject metering into
+/// wasm that is going to be compiled by a metered sys engine, the globals
+/// would clash.
+///
+/// Fails with [`WasmErrorInner::ModuleBuild`] if `wasm` isn't valid.
+pub fn inject_metering(
+    wasm: &[u8],
+    metering_limit: u64,
+    cost_model: CostModel,
+) -> Result<Vec<u8>, wasmer::RuntimeError> {
+    inject(wasm, metering_limit, cost_model)
+        .map_err(|e| wasm_error!(WasmErrorInner::ModuleBuild(e.to_string())).into())
+}
+
+fn inject(
+    wasm: &[u8],
+    metering_limit: u64,
+    cost_model: CostModel,
+) -> Result<Vec<u8>, BinaryReaderError> {
+    validate(wasm)?;
+    let remaining_points = global_count(wasm)?;
+    let points_exhausted = remaining_points + 1;
+
+    let mut injector = Injector::new(
+        wasm,
+        vec![
+            (ValType::I64, ConstExpr::i64_const(metering_limit as i64)),
+            (ValType::I32, ConstExpr::i32_const(0)),
+        ],
+        vec![
+            (REMAINING_POINTS_EXPORT, remaining_points),
+            (POINTS_EXHAUSTED_EXPORT, points_exhausted),
+        ],
+    );
+    let mut code_section = wasm_encoder::CodeSection::new();
+    let mut code_remaining = 0;
+    for payload in Parser::new(0).parse_all(wasm) {
+        let payload = payload?;
+        match &payload {
+            Payload::Version { .. } | Payload::End(_) => {}
+            Payload::CodeSectionStart { count, .. } => {
+                injector.before(CODE_SECTION);
+                code_remaining = *count;
+                if code_remaining == 0 {
+                    injector.module.section(&code_section);
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                code_section.raw(&instrument(
+                    body,
+                    cost_model,
+                    remaining_points,
+                    points_exhausted,
+                )?);
+                code_remaining -= 1;
+                if code_remaining == 0 {
+                    injector.module.section(&code_section);
+                }
+            }
+            _ => {
+                if let Some((id, range)) = payload.as_section() {
+                    injector.copy(id, range)?;
+                }
+            }
+        }
+    }
+    Ok(injector.finish())
+}
+
+/// Copy a function body, charging points at the start of every basic block.
+fn instrument(
+    body: &FunctionBody,
+    cost_model: CostModel,
+    remaining_points: u32,
+    points_exhausted: u32,
+) -> Result<Vec<u8>, BinaryReaderError> {
+    let mut accumulated_cost = 0;
+    rewrite_body(body, |operator, original, instrumented| {
+        // Same algorithm as the metering middleware.
+        accumulated_cost += cost_model.cost(operator);
+        if is_accounting(operator) && accumulated_cost > 0 {
+            for instruction in [
+                Instruction::GlobalGet(remaining_points),
+                Instruction::I64Const(accumulated_cost as i64),
+                Instruction::I64LtU,
+                Instruction::If(BlockType::Empty),
+                Instruction::I32Const(1),
+                Instruction::GlobalSet(points_exhausted),
+                Instruction::Unreachable,
+                Instruction::End,
+                Instruction::GlobalGet(remaining_points),
+                Instruction::I64Const(accumulated_cost as i64),
+                Instruction::I64Sub,
+                Instruction::GlobalSet(remaining_points),
+            ] {
+                instruction.encode(instrumented);
+            }
+            accumulated_cost = 0;
+        }
+        instrumented.extend_from_slice(original);
+    })
+}
+
+/// Possible sources and targets of a branch, as in the metering middleware.
+fn is_accounting(operator: &Operator) -> bool {
+    matches!(
+        operator,
+        Operator::Loop { .. }
+            | Operator::End
+            | Operator::If { .. }
+            | Operator::Else
+            | Operator::Br { .. }
+            | Operator::BrTable { .. }
+            | Operator::BrIf { .. }
+            | Operator::Call { .. }
+            | Operator::CallIndirect { .. }
+            | Operator::Return
+            | Operator::Throw { .. }
+            | Operator::ThrowRef
+            | Operator::Rethrow { .. }
+            | Operator::Delegate { .. }
+            | Operator::Catch { .. }
+            | Operator::ReturnCall { .. }
+            | Operator::ReturnCallIndirect { .. }
+            | Operator::BrOnCast { .. }
+            | Operator::BrOnCastFail { .. }
+            | Operator::CallRef { .. }
+            | Operator::ReturnCallRef { .. }
+            | Operator::BrOnNull { .. }
+            | Operator::BrOnNonNull { .. }
+    )
+}
+
+#[cfg(all(test, feature = "wasmer-sys-cranelift"))]
+mod tests {
+    use super::inject_metering;
+    use crate::module::sys::make_cranelift_engine_with_metering;
+    use crate::module::CostModel;
+    use crate::prelude::*;
+    use wasmer::imports;
+    use wasmer::Engine;
+    use wasmer::Global;
+    use wasmer::Instance;
+    use wasmer::Module;
+    use wasmer::Store;
+    use wasmer::Value;
+
+    const WAT: &[u8] = br#"(module
+        (import "env" "g" (global i32))
+        (global $counter (mut i32) (i32.const 0))
+        (func (export "count") (param i32)
+            (loop $l
+                (global.set $counter (i32.add (global.get $counter) (i32.const 1)))
+                (br_if $l (i32.lt_u (global.get $counter) (local.get 0)))))
+        (export "counter" (global $counter)))"#;
+
+    /// Run `count` on a fresh instance and return the remaining points, or
+    /// `None` if the points ran out.
+    fn count(engine: &Engine, wasm: &[u8], n: i32) -> Option<i64> {
+        let module = Module::new(engine, wasm).unwrap();
+        let mut store = Store::new(engine.clone());
+        let g = Global::new(&mut store, Value::I32(0));
+        let instance =
+            Instance::new(&mut store, &module, &imports! { "env" => { "g" => g } }).unwrap();
+        let result = instance
+            .exports
+            .get_function("count")
+            .unwrap()
+            .call(&mut store, &[Value::I32(n)]);
+        let remaining = instance
+            .exports
+            .get_global("wasmer_metering_remaining_points")
+            .unwrap()
+            .get(&mut store)
+            .unwrap_i64();
+        let exhausted = instance
+            .exports
+            .get_global("wasmer_metering_points_exhausted")
+            .unwrap()
+            .get(&mut store)
+            .unwrap_i32();
+        match result {
+            Ok(_) => {
+                assert_eq!(0, exhausted);
+                // The module's own global is untouched by the new ones.
+                let counter = instance.exports.get_global("counter").unwrap();
+                assert_eq!(n, counter.get(&mut store).unwrap_i32());
+                Some(remaining)
+            }
+            Err(_) => {
+                assert_eq!(1, exhausted);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn same_points_as_middleware() {
+        let wasm = wasmer::wat2wasm(WAT).unwrap();
+        let cost_model = CostModel::default();
+
+        let instrumented = inject_metering(&wasm, 10_000, cost_model).unwrap();
+        let engine = Engine::default();
+        // A metering middleware can only be used for a single module.
+        let middleware_engine = || make_cranelift_engine_with_metering(10_000, cost_model);
+
+        let remaining = count(&engine, &instrumented, 10).unwrap();
+        assert!(remaining < 10_000);
+        assert_eq!(Some(remaining), count(&middleware_engine(), &wasm, 10));
+
+        assert_eq!(None, count(&engine, &instrumented, 10_000));
+        assert_eq!(None, count(&middleware_engine(), &wasm, 10_000));
+    }
+
+    #[test]
+    fn rejects_invalid_wasm() {
+        // Sets the global metering would add, if it got the chance.
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (func (export "f") (result i64)
+                    (global.set 0 (i64.const 1000000000))
+                    (i64.const 0)))"#,
+        )
+        .unwrap();
+        assert!(wasmer::wasmparser::validate(&wasm).is_err());
+        assert!(matches!(
+            inject_metering(&wasm, 100, CostModel::flat())
+                .unwrap_err()
+                .downcast::<WasmError>()
+                .unwrap()
+                .error,
+            WasmErrorInner::ModuleBuild(_),
+        ));
+    }
+
+    #[test]
+    fn adds_missing_sections() {
+        let wasm = wasmer::wat2wasm(br#"(module (func (export "f")))"#).unwrap();
+        let wasm = inject_metering(&wasm, 123, CostModel::flat()).unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm).unwrap();
+        let mut store = Store::new(engine.clone());
+        let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+        instance
+            .exports
+            .get_function("f")
+            .unwrap()
+            .call(&mut store, &[])
+            .unwrap();
+        assert!(
+            instance
+                .exports
+                .get_global("wasmer_metering_remaining_points")
+                .unwrap()
+                .get(&mut store)
+                .unwrap_i64()
+                < 123
+        );
+    }
+}
@@ -0,0 +1,335 @@
+use crate::guest;
+use crate::module::CacheKey;
+use crate::module::InstanceBuilder;
+use crate::module::InstanceWithStore;
+use crate::module::ModuleCache;
+use crate::prelude::*;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use wasmer::AsStoreMut;
+use wasmer::Engine;
+use wasmer::FunctionEnv;
+use wasmer::Imports;
+use wasmer::Instance;
+use wasmer::StoreMut;
+
+/// Builds the imports for every instance in an [`InstancePool`].
+pub type PoolImports = fn(&mut StoreMut, &FunctionEnv<Env>) -> Imports;
+
+/// The default for [`InstancePool::max_idle`].
+pub const DEFAULT_MAX_IDLE: usize = 8;
+
+/// An idle instance waiting in the pool.
+#[derive(Debug)]
+struct Idle {
+    instance_with_store: InstanceWithStore,
+    // Metering points the instance started with, restored before reuse.
+    points: Option<u64>,
+}
+
+/// Reuses instances built from the modules of a [`ModuleCache`].
+///
+/// Building an instance is cheap compared to compiling its module but still
+/// dominates short calls, as every instance needs a new store and its [`Env`]
+/// wired up. The pool keeps instances that are done with around per
+/// [`CacheKey`] so later calls into the same module can skip all that.
+///
+/// Instances are handed out as [`PooledInstance`]s that go back to the pool
+/// when dropped. An instance whose last [`PooledInstance::call`] failed with a
+/// [`WasmErrorInner::maybe_corrupt`] error is dropped instead, so a guest that
+/// may be in a bad state is never called again. Healthy metered instances get
+/// the metering points they started with back before they are reused.
+///
+/// Pooled instances are stateful. Only the metering points are reset when an
+/// instance goes back to the pool, guest memory and globals carry over to the
+/// next [`Self::get`] for the same key, including whatever earlier calls left
+/// in them. Only pool guests that neither rely on starting from a clean state
+/// nor keep data in memory that later callers must not see. Use
+/// [`PooledInstance::discard`] after calls whose state must not be reused, and
+/// [`Self::clear`] to drop every idle instance at once.
+#[derive(Debug)]
+pub struct InstancePool {
+    module_cache: Arc<ModuleCache>,
+    make_store_engine: fn() -> Engine,
+    imports: PoolImports,
+    metered: bool,
+    max_idle: usize,
+    idle: Mutex<BTreeMap<CacheKey, Vec<Idle>>>,
+}
+
+impl InstancePool {
+    /// Construct an empty pool of instances of the modules in `module_cache`.
+    ///
+    /// `make_store_engine` and `imports` are used to build new instances, see
+    /// [`InstanceBuilder::new`] and [`InstanceBuilder::build`].
+    pub fn new(
+        module_cache: Arc<ModuleCache>,
+        make_store_engine: fn() -> Engine,
+        imports: PoolImports,
+    ) -> Self {
+        Self {
+            module_cache,
+            make_store_engine,
+            imports,
+            metered: false,
+            max_idle: DEFAULT_MAX_IDLE,
+            idle: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Build metered instances, see [`InstanceBuilder::metered`].
+    pub fn metered(mut self, metered: bool) -> Self {
+        self.metered = metered;
+        self
+    }
+
+    /// The most idle instances kept per [`CacheKey`]. Instances returned to
+    /// the pool beyond this are dropped. Defaults to [`DEFAULT_MAX_IDLE`].
+    pub fn max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Take an idle instance of the module for `key` from the pool, or build
+    /// a new one if there is none. `wasm` is only used if the module is not
+    /// cached yet, see [`ModuleCache::get`].
+    ///
+    /// An idle instance keeps the state of its earlier calls, see
+    /// [`InstancePool`].
+    pub fn get(
+        &self,
+        key: CacheKey,
+        wasm: &[u8],
+    ) -> Result<PooledInstance<'_>, wasmer::RuntimeError> {
+        let idle = self.idle.lock().get_mut(&key).and_then(Vec::pop);
+        let idle = match idle {
+            Some(idle) => idle,
+            None => self.build(key, wasm)?,
+        };
+        Ok(PooledInstance {
+            pool: self,
+            key,
+            idle: Some(idle),
+            corrupt: false,
+        })
+    }
+
+    /// Number of idle instances in the pool for `key`.
+    pub fn idle(&self, key: &CacheKey) -> usize {
+        self.idle.lock().get(key).map_or(0, Vec::len)
+    }
+
+    /// Drop all idle instances, e.g. after the module for a key changed or to
+    /// make sure no guest state carries over to later calls.
+    pub fn clear(&self) {
+        self.idle.lock().clear();
+    }
+
+    fn build(&self, key: CacheKey, wasm: &[u8]) -> Result<Idle, wasmer::RuntimeError> {
+        let module = self.module_cache.get(key, wasm)?;
+        let instance_with_store = InstanceBuilder::new(module, self.make_store_engine)
+            .metered(self.metered)
+            .build(self.imports)?;
+        let points = if self.metered {
+            guest::remaining_points(
+                &mut instance_with_store.store.lock().as_store_mut(),
+                &instance_with_store.instance,
+            )
+            .map(|(points, _)| points)
+        } else {
+            None
+        };
+        Ok(Idle {
+            instance_with_store,
+            points,
+        })
+    }
+
+    fn put_back(&self, key: CacheKey, idle: Idle) {
+        if let Some(points) = idle.points {
+            let InstanceWithStore { store, instance } = &idle.instance_with_store;
+            if let Err(e) =
+                guest::set_remaining_points(&mut store.lock().as_store_mut(), instance, points)
+            {
+                tracing::warn!("Dropping pooled instance that could not be reset: {:?}", e);
+                return;
+            }
+        }
+        let mut pool = self.idle.lock();
+        let idle_instances = pool.entry(key).or_default();
+        if idle_instances.len() < self.max_idle {
+            idle_instances.push(idle);
+        }
+    }
+}
+
+/// An instance taken from an [`InstancePool`] that goes back to the pool when
+/// dropped, unless it may be corrupt.
+///
+/// The store is not handed out, so every call goes through [`Self::call`],
+/// which checks for corruption. Use [`Self::into_inner`] to use the instance
+/// any other way, which takes it out of the pool for good.
+#[derive(Debug)]
+pub struct PooledInstance<'a> {
+    pool: &'a InstancePool,
+    key: CacheKey,
+    // Only `None` once dropped or discarded.
+    idle: Option<Idle>,
+    corrupt: bool,
+}
+
+impl PooledInstance<'_> {
+    /// Same as [`guest::call`], noting whether the instance may be corrupt
+    /// afterwards.
+    pub fn call<I, O>(&mut self, f: &str, input: I) -> Result<O, wasmer::RuntimeError>
+    where
+        I: serde::Serialize + std::fmt::Debug,
+        O: serde::de::DeserializeOwned + std::fmt::Debug,
+    {
+        let InstanceWithStore { store, instance } = self.instance_with_store();
+        let result = guest::call(&mut store.lock().as_store_mut(), instance.clone(), f, input);
+        if let Err(e) = &result {
+            self.corrupt |= maybe_corrupt(e);
+        }
+        result
+    }
+
+    /// The instance, e.g. to look at its exports. Calls go through
+    /// [`Self::call`].
+    pub fn instance(&self) -> &Arc<Instance> {
+        &self.instance_with_store().instance
+    }
+
+    /// Same as [`guest::remaining_points`].
+    pub fn remaining_points(&self) -> Option<(u64, bool)> {
+        let InstanceWithStore { store, instance } = self.instance_with_store();
+        guest::remaining_points(&mut store.lock().as_store_mut(), instance)
+    }
+
+    /// Take the instance out of the pool for good.
+    pub fn into_inner(mut self) -> InstanceWithStore {
+        self.idle
+            .take()
+            .expect("Pooled instance used after discard. This is a bug.")
+            .instance_with_store
+    }
+
+    /// Drop the instance rather than returning it to the pool.
+    pub fn discard(mut self) {
+        self.idle = None;
+    }
+
+    fn instance_with_store(&self) -> &InstanceWithStore {
+        &self
+            .idle
+            .as_ref()
+            .expect("Pooled instance used after discard. This is a bug.")
+            .instance_with_store
+    }
+}
+
+impl Drop for PooledInstance<'_> {
+    fn drop(&mut self) {
+        if let Some(idle) = self.idle.take() {
+            if !self.corrupt {
+                self.pool.put_back(self.key, idle);
+            }
+        }
+    }
+}
+
+/// Errors that aren't a [`WasmError`] come from wasmer itself, e.g. a trap,
+/// so there is no telling what state they left the guest in.
+fn maybe_corrupt(error: &wasmer::RuntimeError) -> bool {
+    error
+        .downcast_ref::<WasmError>()
+        .is_none_or(|e| e.error.maybe_corrupt())
+}
+
+#[cfg(all(test, feature = "wasmer-sys-cranelift"))]
+mod tests {
+    use super::InstancePool;
+    use crate::module::sys;
+    use crate::module::ModuleCache;
+    use crate::prelude::*;
+    use std::sync::Arc;
+    use wasmer::Imports;
+
+    /// `ok` returns `Ok(())`. Every other call returns a null pointer to no
+    /// output, which can't be deserialized, unless the guest traps first.
+    fn wasm() -> Vec<u8> {
+        let output = holochain_serialized_bytes::encode(&Ok::<(), WasmError>(())).unwrap();
+        let data: String = output.iter().map(|byte| format!("\\{byte:02x}")).collect();
+        // The output goes past where the host writes the input to.
+        let output_ptr_len = (1024 << 32) | output.len() as i64;
+        wasmer::wat2wasm(
+            format!(
+                r#"(module
+                    (memory (export "memory") 1)
+                    (data (i32.const 1024) "{data}")
+                    (func (export "__hc__allocate_1") (param i32) (result i32) i32.const 0)
+                    (func (export "__hc__deallocate_1") (param i32 i32))
+                    (func (export "ok") (param i32 i32) (result i64) i64.const {output_ptr_len})
+                    (func (export "noop") (param i32 i32) (result i64) i64.const 0)
+                    (func (export "trap") (param i32 i32) (result i64) unreachable))"#
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+        .into_owned()
+    }
+
+    fn pool() -> InstancePool {
+        let cache = ModuleCache::new(sys::make_cranelift_engine, sys::make_runtime_engine, None);
+        InstancePool::new(Arc::new(cache), sys::make_runtime_engine, |_, _| {
+            Imports::new()
+        })
+        .metered(true)
+    }
+
+    #[test]
+    fn recycles_healthy_instances() {
+        let pool = pool();
+        let wasm = wasm();
+        let key = [0; 32];
+
+        let mut pooled = pool.get(key, &wasm).unwrap();
+        let instance = pooled.instance().clone();
+        let points = pooled.remaining_points();
+        pooled.call::<_, ()>("ok", ()).unwrap();
+        let (points_after_call, _) = pooled.remaining_points().unwrap();
+        assert!(points_after_call < points.unwrap().0);
+        drop(pooled);
+        assert_eq!(1, pool.idle(&key));
+
+        let mut pooled = pool.get(key, &wasm).unwrap();
+        assert!(Arc::ptr_eq(&instance, pooled.instance()));
+        assert_eq!(points, pooled.remaining_points());
+
+        // Failing without corrupting the guest is healthy too.
+        let error = pooled.call::<_, ()>("noop", ()).unwrap_err();
+        assert!(!error.downcast::<WasmError>().unwrap().error.maybe_corrupt());
+        drop(pooled);
+        let pooled = pool.get(key, &wasm).unwrap();
+        assert!(Arc::ptr_eq(&instance, pooled.instance()));
+        assert_eq!(points, pooled.remaining_points());
+    }
+
+    #[test]
+    fn discards_corrupt_instances() {
+        let pool = pool();
+        let wasm = wasm();
+        let key = [0; 32];
+
+        let mut pooled = pool.get(key, &wasm).unwrap();
+        pooled.call::<_, ()>("trap", ()).unwrap_err();
+        drop(pooled);
+        assert_eq!(0, pool.idle(&key));
+
+        pool.get(key, &wasm).unwrap().discard();
+        assert_eq!(0, pool.idle(&key));
+
+        drop(pool.get(key, &wasm).unwrap().into_inner());
+        assert_eq!(0, pool.idle(&key));
+    }
+}
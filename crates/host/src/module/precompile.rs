@@ -0,0 +1,105 @@
+use crate::module::CacheKey;
+use parking_lot::Condvar;
+use parking_lot::Mutex;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+use wasmer::Module;
+
+#[derive(Default)]
+struct State {
+    result: Option<Result<Arc<Module>, wasmer::RuntimeError>>,
+    wakers: Vec<Waker>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    finished: Condvar,
+}
+
+/// A module being precompiled by [`crate::module::ModuleCache::precompile`].
+///
+/// The module is in the cache whether or not anyone looks at the handle, so
+/// it can be dropped if the result isn't interesting. Otherwise the result
+/// can be waited for with [`Self::wait`], polled with [`Self::try_result`]
+/// or awaited, as the handle is a [`Future`].
+#[derive(Clone)]
+pub struct PrecompileHandle {
+    key: CacheKey,
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for PrecompileHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrecompileHandle")
+            .field("key", &self.key)
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+impl PrecompileHandle {
+    pub(crate) fn new(key: CacheKey) -> Self {
+        Self {
+            key,
+            shared: Arc::default(),
+        }
+    }
+
+    pub(crate) fn finish(&self, result: Result<Arc<Module>, wasmer::RuntimeError>) {
+        let wakers = {
+            let mut state = self.shared.state.lock();
+            state.result = Some(result);
+            std::mem::take(&mut state.wakers)
+        };
+        self.shared.finished.notify_all();
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// The cache key of the module.
+    pub fn key(&self) -> CacheKey {
+        self.key
+    }
+
+    /// Whether the module is done, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        self.shared.state.lock().result.is_some()
+    }
+
+    /// The module, or why it couldn't be built, if it is done.
+    pub fn try_result(&self) -> Option<Result<Arc<Module>, wasmer::RuntimeError>> {
+        self.shared.state.lock().result.clone()
+    }
+
+    /// Block until the module is done.
+    pub fn wait(&self) -> Result<Arc<Module>, wasmer::RuntimeError> {
+        let mut state = self.shared.state.lock();
+        loop {
+            if let Some(result) = &state.result {
+                return result.clone();
+            }
+            self.shared.finished.wait(&mut state);
+        }
+    }
+}
+
+impl Future for PrecompileHandle {
+    type Output = Result<Arc<Module>, wasmer::RuntimeError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.state.lock();
+        match &state.result {
+            Some(result) => Poll::Ready(result.clone()),
+            None => {
+                if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    state.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
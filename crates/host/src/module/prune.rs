@@ -0,0 +1,192 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// Temporary files left behind by writes that never finished, e.g. because
+/// the process crashed, are removed once they are this old.
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Limits on the serialized modules a [`crate::module::ModuleCache`] keeps on
+/// the filesystem, see [`crate::module::ModuleCache::filesystem_budget`].
+///
+/// Modules are ordered by when they were last written or loaded, going by
+/// their modification time, and the least recently used are removed first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FilesystemBudget {
+    /// Total size of all modules on the filesystem.
+    pub max_size: Option<u64>,
+    /// How long a module may go unused before it is removed.
+    pub max_age: Option<Duration>,
+}
+
+/// What [`crate::module::ModuleCache::prune`] removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pruned {
+    /// Number of files removed.
+    pub files: u64,
+    /// Total size of the files removed.
+    pub bytes: u64,
+}
+
+/// Whether a file is named like the serialized module of a cache key.
+pub(crate) fn is_module_file_name(name: &OsStr) -> bool {
+    is_hex_of_len(name, std::mem::size_of::<crate::module::CacheKey>())
+}
+
+/// Whether a directory is named like the fingerprint directories that
+/// serialized modules are kept in.
+pub(crate) fn is_fingerprint_dir_name(name: &OsStr) -> bool {
+    is_hex_of_len(name, crate::module::FINGERPRINT_DIR_LEN)
+}
+
+fn is_hex_of_len(name: &OsStr, len: usize) -> bool {
+    name.to_str()
+        .and_then(|name| hex::decode(name).ok())
+        .is_some_and(|bytes| bytes.len() == len)
+}
+
+/// A file that may be removed.
+struct Candidate {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Remove serialized modules under `filesystem_path` from all fingerprint
+/// directories until they fit in `budget`, along with stale temporary files
+/// and fingerprint directories that end up empty, except for `keep_dir`.
+///
+/// Modules written straight into `filesystem_path`, from before modules were
+/// kept apart by fingerprint, are pruned like those of other fingerprints.
+///
+/// Also returns the total size of the modules left.
+pub(crate) fn prune(
+    filesystem_path: &Path,
+    keep_dir: &Path,
+    budget: &FilesystemBudget,
+) -> Result<(Pruned, u64), std::io::Error> {
+    let now = SystemTime::now();
+    let age = |modified: SystemTime| now.duration_since(modified).unwrap_or_default();
+    let mut pruned = Pruned::default();
+    let mut modules = Vec::new();
+    let mut dirs = Vec::new();
+    let Some(entries) = skip_not_found(std::fs::read_dir(filesystem_path))? else {
+        return Ok((pruned, 0));
+    };
+    for entry in entries {
+        let entry = entry?;
+        let Some(file_type) = skip_not_found(entry.file_type())? else {
+            continue;
+        };
+        if file_type.is_file() && is_module_file_name(&entry.file_name()) {
+            let Some(metadata) = skip_not_found(entry.metadata())? else {
+                continue;
+            };
+            modules.push(Candidate {
+                path: entry.path(),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+            continue;
+        }
+        if !is_fingerprint_dir_name(&entry.file_name()) || !file_type.is_dir() {
+            continue;
+        }
+        let Some(files) = skip_not_found(std::fs::read_dir(entry.path()))? else {
+            continue;
+        };
+        for file in files {
+            let file = file?;
+            let Some(metadata) = skip_not_found(file.metadata())? else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            let candidate = Candidate {
+                path: file.path(),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            };
+            let name = file.file_name();
+            if is_module_file_name(&name) {
+                modules.push(candidate);
+            } else if name.to_string_lossy().ends_with(".tmp")
+                && age(candidate.modified) > STALE_TEMP_FILE_AGE
+            {
+                remove(candidate, &mut pruned)?;
+            }
+        }
+        if entry.path() != keep_dir {
+            dirs.push(entry.path());
+        }
+    }
+
+    // Oldest last, so they can be popped off.
+    modules.sort_by_key(|module| std::cmp::Reverse(module.modified));
+    let mut size: u64 = modules.iter().map(|module| module.size).sum();
+    while let Some(oldest) = modules.pop() {
+        let too_old = budget
+            .max_age
+            .is_some_and(|max_age| age(oldest.modified) > max_age);
+        let too_big = budget.max_size.is_some_and(|max_size| size > max_size);
+        if !too_old && !too_big {
+            break;
+        }
+        size -= oldest.size;
+        remove(oldest, &mut pruned)?;
+    }
+
+    for dir in dirs {
+        // Only succeeds for empty directories, and it doesn't matter if
+        // another cache just started using one.
+        let _ = std::fs::remove_dir(dir);
+    }
+    Ok((pruned, size))
+}
+
+/// Remove the modules written straight into `filesystem_path`, from before
+/// modules were kept apart by fingerprint. No builder uses them any more.
+pub(crate) fn remove_legacy_modules(filesystem_path: &Path) -> Result<(), std::io::Error> {
+    let entries = match std::fs::read_dir(filesystem_path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        entries => entries?,
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() || !is_module_file_name(&entry.file_name()) {
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// `None` if what was being looked at is gone, e.g. because another cache
+/// sharing the directory pruned it first, in which case there is nothing left
+/// to do with it.
+fn skip_not_found<T>(result: Result<T, std::io::Error>) -> Result<Option<T>, std::io::Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn remove(candidate: Candidate, pruned: &mut Pruned) -> Result<(), std::io::Error> {
+    match std::fs::remove_file(&candidate.path) {
+        Ok(()) => {
+            pruned.files += 1;
+            pruned.bytes += candidate.size;
+            Ok(())
+        }
+        // Someone else got there first.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// A snapshot of what a [`crate::module::ModuleCache`] has been doing since it
+/// was constructed, see [`crate::module::ModuleCache::stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModuleCacheStats {
+    /// Modules found in memory.
+    pub hits: u64,
+    /// Modules not found in memory, which were then either found on the
+    /// filesystem or compiled.
+    pub misses: u64,
+    /// Modules deserialized from the filesystem.
+    pub filesystem_hits: u64,
+    /// Files on the filesystem that failed to deserialize and were removed.
+    pub corrupt_files: u64,
+    /// Modules that could not be written to the filesystem. They were still
+    /// cached in memory.
+    pub filesystem_write_errors: u64,
+    /// Modules compiled from wasm.
+    pub compiles: u64,
+    /// Total time spent compiling modules from wasm.
+    pub compile_duration: Duration,
+    /// Modules evicted from memory to make room for others.
+    pub evictions: u64,
+    /// Modules currently in memory.
+    pub modules: usize,
+    /// Total serialized size of the modules currently in memory, or the size
+    /// of their wasm for modules that were never serialized, see
+    /// [`crate::module::ModuleCache::memory_budget`].
+    pub resident_size: usize,
+    /// The most modules that can be kept in memory.
+    pub capacity: usize,
+}
+
+/// Counters behind [`ModuleCacheStats`] that are updated without taking the
+/// lock on the in-memory cache.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) hits: AtomicU64,
+    pub(crate) misses: AtomicU64,
+    pub(crate) filesystem_hits: AtomicU64,
+    pub(crate) corrupt_files: AtomicU64,
+    pub(crate) filesystem_write_errors: AtomicU64,
+    pub(crate) compiles: AtomicU64,
+    pub(crate) compile_nanos: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_compile(&self, duration: Duration) {
+        Self::increment(&self.compiles);
+        self.compile_nanos.fetch_add(
+            u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Stats from the counters, with the in-memory cache fields left for the
+    /// caller to fill in.
+    pub(crate) fn stats(&self) -> ModuleCacheStats {
+        ModuleCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            filesystem_hits: self.filesystem_hits.load(Ordering::Relaxed),
+            corrupt_files: self.corrupt_files.load(Ordering::Relaxed),
+            filesystem_write_errors: self.filesystem_write_errors.load(Ordering::Relaxed),
+            compiles: self.compiles.load(Ordering::Relaxed),
+            compile_duration: Duration::from_nanos(self.compile_nanos.load(Ordering::Relaxed)),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::module::prune;
+use crate::module::CacheKey;
+use crate::module::FilesystemBudget;
+use crate::module::ModuleBuilder;
+use crate::module::Pruned;
+use crate::module::FINGERPRINT_DIR_LEN;
+use bytes::BufMut;
+use bytes::Bytes;
+use bytes::BytesMut;
+use parking_lot::Mutex;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How often [`FilesystemModuleStore::put`] prunes while the modules it knows
+/// of fit in the budget, to catch modules that got too old or were written by
+/// other caches sharing the path.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where a [`crate::module::ModuleCache`] keeps serialized modules so that
+/// they outlive the in-memory cache, see
+/// [`crate::module::ModuleCache::serialized_module_store`].
+///
+/// The cache stores opaque bytes under their cache key. It checks everything
+/// it reads back, so a store doesn't need to guard against corruption, and
+/// anything it fails to read or write is treated as a miss rather than an
+/// error for the caller.
+///
+/// Modules are only usable by caches whose builders have the same
+/// [`ModuleBuilder::fingerprint`], so a store shared between differently
+/// configured caches should keep their modules apart by fingerprint, as
+/// [`FilesystemModuleStore`] does.
+pub trait SerializedModuleStore: std::fmt::Debug + Send + Sync {
+    /// The bytes stored under `key`, or `None` if there are none.
+    fn get(&self, key: &CacheKey) -> Result<Option<Bytes>, std::io::Error>;
+
+    /// Store `bytes` under `key`.
+    ///
+    /// Concurrent gets of the same module can put the same bytes more than
+    /// once. Stores may keep either and fail with
+    /// [`std::io::ErrorKind::AlreadyExists`], which isn't counted as an error.
+    fn put(&self, key: &CacheKey, bytes: &[u8]) -> Result<(), std::io::Error>;
+
+    /// Remove whatever is stored under `key`. Removing a key that isn't
+    /// stored is not an error.
+    fn remove(&self, key: &CacheKey) -> Result<(), std::io::Error>;
+
+    /// Keys of everything stored, in no particular order.
+    fn list(&self) -> Result<Vec<CacheKey>, std::io::Error>;
+
+    /// Remove everything stored, see
+    /// [`crate::module::ModuleCache::clear_filesystem`]. Removes everything
+    /// [`Self::list`] lists by default.
+    fn clear(&self) -> Result<(), std::io::Error> {
+        for key in self.list()? {
+            self.remove(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Remove whatever the store doesn't want to keep any more, see
+    /// [`crate::module::ModuleCache::prune`]. Nothing by default.
+    fn prune(&self) -> Result<Pruned, std::io::Error> {
+        Ok(Pruned::default())
+    }
+}
+
+/// Keeps serialized modules in files named after their cache keys.
+///
+/// The files go in a subdirectory of `filesystem_path` named after the start
+/// of the builder's [`ModuleBuilder::fingerprint`], so that caches with
+/// differently configured builders can share the path. This is the store of
+/// a [`crate::module::ModuleCache`] constructed with a filesystem path.
+#[derive(Debug)]
+pub struct FilesystemModuleStore {
+    filesystem_path: PathBuf,
+    dir_path: PathBuf,
+    budget: Option<FilesystemBudget>,
+    tracked: Mutex<Tracked>,
+}
+
+/// What a store knows about its modules since it last pruned.
+#[derive(Debug, Default)]
+struct Tracked {
+    /// Total size of the modules left by the last prune, plus those written
+    /// since.
+    size: u64,
+    /// `None` until the first prune.
+    last_pruned: Option<Instant>,
+}
+
+impl FilesystemModuleStore {
+    /// A store for modules built by `builder` under `filesystem_path`, which
+    /// must exist.
+    pub fn new(filesystem_path: PathBuf, builder: &ModuleBuilder) -> Self {
+        let dir_path =
+            filesystem_path.join(hex::encode(&builder.fingerprint()[..FINGERPRINT_DIR_LEN]));
+        Self {
+            filesystem_path,
+            dir_path,
+            budget: None,
+            tracked: Mutex::default(),
+        }
+    }
+
+    /// Remove the least recently used modules whenever they go over `budget`
+    /// after a write, see [`SerializedModuleStore::prune`].
+    ///
+    /// Pruning scans every module under `filesystem_path`, so writes don't
+    /// prune every time. They prune once the modules written since the last
+    /// prune take the size over `max_size`, and otherwise at most once a
+    /// minute.
+    pub fn budget(mut self, budget: FilesystemBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// The directory the modules of this store's builder are kept in.
+    pub fn dir_path(&self) -> &Path {
+        &self.dir_path
+    }
+
+    /// Get filesystem cache path for a given key
+    pub(crate) fn module_path(&self, key: &CacheKey) -> PathBuf {
+        self.dir_path.join(hex::encode(key))
+    }
+
+    /// Write a file such that it either has all of `bytes` or doesn't exist.
+    ///
+    /// The bytes are written to a temporary file in the same directory that
+    /// is synced to disk and only then moved to `path`, so a crash or a full
+    /// disk can't leave a truncated module behind to be deserialized later.
+    /// The move fails if `path` exists so that cache stampedes don't cause
+    /// corruption. Each file can only be written once. On Unix the directory
+    /// is synced after the move too, otherwise the move itself may not
+    /// survive a crash.
+    fn write(&self, path: &Path, bytes: &[u8]) -> Result<(), std::io::Error> {
+        // Only the fingerprint directory is created, a missing cache
+        // directory is a misconfiguration.
+        match std::fs::create_dir(&self.dir_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+        // The leading dot keeps temporary files from looking like cache keys.
+        let mut file = tempfile::Builder::new()
+            .prefix(".")
+            .suffix(".tmp")
+            .tempfile_in(&self.dir_path)?;
+        file.write_all(bytes)?;
+        file.as_file().sync_all()?;
+        file.persist_noclobber(path).map_err(|e| e.error)?;
+        #[cfg(unix)]
+        File::open(&self.dir_path)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Note that a module of `size` bytes was written, and tell whether
+    /// that is reason to prune, see [`Self::budget`].
+    fn should_prune(&self, size: u64) -> bool {
+        let Some(budget) = self.budget else {
+            return false;
+        };
+        let mut tracked = self.tracked.lock();
+        tracked.size += size;
+        budget
+            .max_size
+            .is_some_and(|max_size| tracked.size > max_size)
+            || tracked
+                .last_pruned
+                .is_none_or(|last_pruned| last_pruned.elapsed() >= PRUNE_INTERVAL)
+    }
+}
+
+impl SerializedModuleStore for FilesystemModuleStore {
+    /// Reading a module marks it as recently used, so that it is pruned last.
+    ///
+    /// Marking it is best effort. Modules can still be read from a read-only
+    /// directory, they just don't get marked.
+    fn get(&self, key: &CacheKey) -> Result<Option<Bytes>, std::io::Error> {
+        let module_path = self.module_path(key);
+        let mut file = match File::open(&module_path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            file => file?,
+        };
+        if let Err(e) = File::options()
+            .write(true)
+            .open(&module_path)
+            .and_then(|file| file.set_modified(std::time::SystemTime::now()))
+        {
+            tracing::debug!("{} Path: {}", e, module_path.display());
+        }
+
+        // Read file into `Bytes` instead of `Vec<u8>` so that the clone is cheap
+        let mut bytes_mut = BytesMut::new().writer();
+        std::io::copy(&mut file, &mut bytes_mut)?;
+        Ok(Some(bytes_mut.into_inner().freeze()))
+    }
+
+    fn put(&self, key: &CacheKey, bytes: &[u8]) -> Result<(), std::io::Error> {
+        self.write(&self.module_path(key), bytes)?;
+        if self.should_prune(bytes.len() as u64) {
+            if let Err(e) = self.prune() {
+                tracing::warn!("Failed to prune filesystem cache: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, key: &CacheKey) -> Result<(), std::io::Error> {
+        match std::fs::remove_file(self.module_path(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Only files named like cache keys are listed, so anything else that
+    /// happens to be in the directory is left alone.
+    fn list(&self) -> Result<Vec<CacheKey>, std::io::Error> {
+        let entries = match std::fs::read_dir(&self.dir_path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            entries => entries?,
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !prune::is_module_file_name(&entry.file_name()) || !entry.file_type()?.is_file() {
+                continue;
+            }
+            let mut key = CacheKey::default();
+            if hex::decode_to_slice(entry.file_name().as_encoded_bytes(), &mut key).is_ok() {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Modules written straight into `filesystem_path`, from before modules
+    /// were kept apart by fingerprint, are removed too.
+    fn clear(&self) -> Result<(), std::io::Error> {
+        for key in self.list()? {
+            self.remove(&key)?;
+        }
+        prune::remove_legacy_modules(&self.filesystem_path)
+    }
+
+    /// Remove modules until they fit in the budget, if any.
+    ///
+    /// Modules serialized by builders with other fingerprints, e.g. from
+    /// before a wasmer upgrade, count towards the budget too and as they are
+    /// never used again they are the first to go. So do modules written
+    /// straight into `filesystem_path` by versions that didn't keep modules
+    /// apart by fingerprint. Temporary files left by interrupted writes are
+    /// removed as well.
+    fn prune(&self) -> Result<Pruned, std::io::Error> {
+        let (pruned, size) = prune::prune(
+            &self.filesystem_path,
+            &self.dir_path,
+            &self.budget.unwrap_or_default(),
+        )?;
+        *self.tracked.lock() = Tracked {
+            size,
+            last_pruned: Some(Instant::now()),
+        };
+        Ok(pruned)
+    }
+}
@@ -1,28 +1,55 @@
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::ptr::NonNull;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
+use wasmer::sys::vm::ExpectedValue;
+use wasmer::sys::vm::LinearMemory;
+use wasmer::sys::vm::MemoryError;
+use wasmer::sys::vm::MemoryStyle;
+use wasmer::sys::vm::TableStyle;
+use wasmer::sys::vm::ThreadConditions;
+use wasmer::sys::vm::Trap;
+use wasmer::sys::vm::VMExtern;
+use wasmer::sys::vm::VMMemory;
+use wasmer::sys::vm::VMMemoryDefinition;
+use wasmer::sys::vm::VMTable;
+use wasmer::sys::vm::VMTableDefinition;
+use wasmer::sys::vm::WaiterError;
 use wasmer::sys::BaseTunables;
 use wasmer::sys::CompilerConfig;
 use wasmer::sys::NativeEngineExt;
+use wasmer::sys::Target;
+use wasmer::sys::Tunables;
 use wasmer::wasmparser;
+use wasmer::AsStoreMut;
 use wasmer::Engine;
+use wasmer::Instance;
+use wasmer::MemoryType;
+use wasmer::Pages;
+use wasmer::StoreMut;
+use wasmer::TableType;
 use wasmer_middlewares::Metering;
 
-#[cfg(not(test))]
-/// one hundred giga ops
-pub const WASM_METERING_LIMIT: u64 = 100_000_000_000;
-
-#[cfg(test)]
-/// ten mega ops.
-/// We don't want tests to run forever, and it can take several minutes for 100 giga ops to run.
-pub const WASM_METERING_LIMIT: u64 = 10_000_000;
+pub use crate::module::CostModel;
+pub use crate::module::WASM_METERING_LIMIT;
 
 /// Configure a compiler with the metering middleware and our standard
 /// nans-canonicalisation setting. Shared by both per-compiler factories below
 /// so the metering policy lives in one place.
-fn configure_compiler<C: CompilerConfig>(compiler: &mut C) {
-    let cost_function = |_operator: &wasmparser::Operator| -> u64 { 1 };
-    // @todo 100 giga-ops is totally arbitrary cutoff so we probably
-    // want to make the limit configurable somehow.
-    let metering = Arc::new(Metering::new(WASM_METERING_LIMIT, cost_function));
+///
+/// `metering_limit` is the number of points every new instance starts with.
+/// It can be changed per call with [`crate::guest::set_remaining_points`].
+fn configure_compiler<C: CompilerConfig>(
+    compiler: &mut C,
+    metering_limit: u64,
+    cost_model: CostModel,
+) {
+    let cost_function = move |operator: &wasmparser::Operator| -> u64 { cost_model.cost(operator) };
+    let metering = Arc::new(Metering::new(metering_limit, cost_function));
     compiler.canonicalize_nans(true);
     compiler.push_middleware(metering);
 }
@@ -41,18 +68,66 @@ fn apply_tunables(mut engine: Engine) -> Engine {
 }
 
 /// Build a sys engine backed by the Cranelift compiler.
+///
+/// Instances start with [`WASM_METERING_LIMIT`] points and are charged 1
+/// point per operator, see [`CostModel::flat`].
 #[cfg(feature = "wasmer-sys-cranelift")]
 pub fn make_cranelift_engine() -> Engine {
+    make_cranelift_engine_with_metering(WASM_METERING_LIMIT, CostModel::flat())
+}
+
+/// Build a sys engine backed by the Cranelift compiler whose instances start
+/// with `metering_limit` points.
+///
+/// [`crate::module::ModuleBuilder::new`] takes a plain `fn() -> Engine`, so
+/// wrap this in a non-capturing closure to use it there, e.g.
+/// `|| make_cranelift_engine_with_metering_limit(1_000_000)`.
+#[cfg(feature = "wasmer-sys-cranelift")]
+pub fn make_cranelift_engine_with_metering_limit(metering_limit: u64) -> Engine {
+    make_cranelift_engine_with_metering(metering_limit, CostModel::flat())
+}
+
+/// Build a sys engine backed by the Cranelift compiler whose instances start
+/// with `metering_limit` points and are charged according to `cost_model`.
+///
+/// This is the only way to meter with anything but [`CostModel::flat`], e.g.
+/// the weighted [`CostModel::default`]. Hosts that validate the same calls
+/// must all use the same cost model, or the same call can run out of points
+/// on one host and succeed on another.
+#[cfg(feature = "wasmer-sys-cranelift")]
+pub fn make_cranelift_engine_with_metering(metering_limit: u64, cost_model: CostModel) -> Engine {
     let mut compiler = wasmer::sys::Cranelift::default();
-    configure_compiler(&mut compiler);
+    configure_compiler(&mut compiler, metering_limit, cost_model);
     apply_tunables(Engine::from(compiler))
 }
 
 /// Build a sys engine backed by the LLVM compiler.
+///
+/// Instances start with [`WASM_METERING_LIMIT`] points and are charged 1
+/// point per operator, see [`CostModel::flat`].
 #[cfg(feature = "wasmer-sys-llvm")]
 pub fn make_llvm_engine() -> Engine {
+    make_llvm_engine_with_metering(WASM_METERING_LIMIT, CostModel::flat())
+}
+
+/// Build a sys engine backed by the LLVM compiler whose instances start with
+/// `metering_limit` points.
+///
+/// See [`make_cranelift_engine_with_metering_limit`] for how to pass this to
+/// [`crate::module::ModuleBuilder::new`].
+#[cfg(feature = "wasmer-sys-llvm")]
+pub fn make_llvm_engine_with_metering_limit(metering_limit: u64) -> Engine {
+    make_llvm_engine_with_metering(metering_limit, CostModel::flat())
+}
+
+/// Build a sys engine backed by the LLVM compiler whose instances start with
+/// `metering_limit` points and are charged according to `cost_model`.
+///
+/// See [`make_cranelift_engine_with_metering`] for picking a cost model.
+#[cfg(feature = "wasmer-sys-llvm")]
+pub fn make_llvm_engine_with_metering(metering_limit: u64, cost_model: CostModel) -> Engine {
     let mut compiler = wasmer::sys::LLVM::default();
-    configure_compiler(&mut compiler);
+    configure_compiler(&mut compiler, metering_limit, cost_model);
     apply_tunables(Engine::from(compiler))
 }
 
@@ -78,11 +153,239 @@ pub fn make_runtime_engine() -> Engine {
     Engine::headless()
 }
 
+/// Same as [`make_runtime_engine`] but memories of instances in stores built
+/// from this engine can't grow beyond `max_pages` wasm pages.
+///
+/// `memory.grow` past the limit fails in the guest, which typically makes it
+/// abort, and calls that end this way fail with
+/// [`WasmErrorInner::MemoryLimit`](crate::prelude::WasmErrorInner::MemoryLimit).
+/// Instantiating a module whose memory starts out bigger than the limit fails.
+///
+/// As with the metering limit, wrap this in a non-capturing closure to pass it
+/// to [`crate::module::InstanceBuilder::new`], e.g.
+/// `|| make_runtime_engine_with_memory_limit(1_024)`.
+pub fn make_runtime_engine_with_memory_limit(max_pages: u32) -> Engine {
+    let mut engine = Engine::headless();
+    engine.set_tunables(LimitingTunables {
+        base: BaseTunables::for_target(&Target::default()),
+        max_pages: Pages(max_pages),
+    });
+    engine
+}
+
+/// Failed attempts to grow each live memory created by [`LimitingTunables`],
+/// by the address of the memory's definition, so that the count can be found
+/// from an instance.
+///
+/// Guests can run on any thread, e.g. with async calls, so the count lives
+/// with the memory rather than the thread. Each memory bumps its own counter,
+/// so the map is only written when a memory is created or dropped.
+static LIMITED_MEMORIES: RwLock<BTreeMap<usize, Arc<AtomicU64>>> =
+    parking_lot::const_rwlock(BTreeMap::new());
+
+/// The number of entries in [`LIMITED_MEMORIES`], so that calls can skip the
+/// lookup while no memory is limited, which is the usual case.
+static LIMITED_MEMORY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// How many times the memory an instance exports as "memory" failed to grow
+/// past its limit so far.
+pub(crate) fn memory_limit_hits(store_mut: &mut StoreMut, instance: &Instance) -> u64 {
+    if LIMITED_MEMORY_COUNT.load(Ordering::Relaxed) == 0 || !store_mut.engine().is_sys() {
+        return 0;
+    }
+    let Some(memory) = instance.exports.get_extern("memory") else {
+        return 0;
+    };
+    match memory.to_vm_extern().into_sys() {
+        VMExtern::Memory(handle) => {
+            let address = handle
+                .get(store_mut.objects_mut().as_sys())
+                .vmmemory()
+                .as_ptr()
+                .addr();
+            LIMITED_MEMORIES
+                .read()
+                .get(&address)
+                .map_or(0, |hits| hits.load(Ordering::Relaxed))
+        }
+        _ => 0,
+    }
+}
+
+/// Tunables that cap the maximum size of every memory created with them.
+///
+/// Only the memory types are changed, memory styles are decided when compiling
+/// so the limit doesn't need to be known until instantiation.
+struct LimitingTunables<T: Tunables> {
+    base: T,
+    max_pages: Pages,
+}
+
+impl<T: Tunables> LimitingTunables<T> {
+    /// The memory type with its maximum lowered to the limit if needed.
+    fn limit(&self, ty: &MemoryType) -> Result<MemoryType, MemoryError> {
+        if ty.minimum > self.max_pages {
+            return Err(MemoryError::MinimumMemoryTooLarge {
+                min_requested: ty.minimum,
+                max_allowed: self.max_pages,
+            });
+        }
+        let mut ty = *ty;
+        ty.maximum = Some(ty.maximum.map_or(self.max_pages, |m| m.min(self.max_pages)));
+        Ok(ty)
+    }
+}
+
+impl<T: Tunables> Tunables for LimitingTunables<T> {
+    fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
+        self.base.memory_style(memory)
+    }
+
+    fn table_style(&self, table: &TableType) -> TableStyle {
+        self.base.table_style(table)
+    }
+
+    fn create_host_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+    ) -> Result<VMMemory, MemoryError> {
+        let memory = self.base.create_host_memory(&self.limit(ty)?, style)?;
+        Ok(VMMemory(LimitedMemory::new(memory.0)))
+    }
+
+    unsafe fn create_vm_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+        vm_definition_location: NonNull<VMMemoryDefinition>,
+    ) -> Result<VMMemory, MemoryError> {
+        let memory = unsafe {
+            self.base
+                .create_vm_memory(&self.limit(ty)?, style, vm_definition_location)?
+        };
+        Ok(VMMemory(LimitedMemory::new(memory.0)))
+    }
+
+    fn create_host_table(&self, ty: &TableType, style: &TableStyle) -> Result<VMTable, String> {
+        self.base.create_host_table(ty, style)
+    }
+
+    unsafe fn create_vm_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+        vm_definition_location: NonNull<VMTableDefinition>,
+    ) -> Result<VMTable, String> {
+        unsafe { self.base.create_vm_table(ty, style, vm_definition_location) }
+    }
+}
+
+/// A memory created by [`LimitingTunables`], which notes when it fails to grow
+/// past its limit, see [`memory_limit_hits`].
+#[derive(Debug)]
+struct LimitedMemory(Box<dyn LinearMemory + 'static>, Arc<AtomicU64>);
+
+impl LimitedMemory {
+    fn new(memory: Box<dyn LinearMemory + 'static>) -> Box<Self> {
+        let hits = Arc::new(AtomicU64::new(0));
+        let mut limited_memories = LIMITED_MEMORIES.write();
+        limited_memories.insert(memory.vmmemory().as_ptr().addr(), hits.clone());
+        LIMITED_MEMORY_COUNT.store(limited_memories.len(), Ordering::Relaxed);
+        Box::new(Self(memory, hits))
+    }
+}
+
+impl LinearMemory for LimitedMemory {
+    fn ty(&self) -> MemoryType {
+        self.0.ty()
+    }
+
+    fn size(&self) -> Pages {
+        self.0.size()
+    }
+
+    fn style(&self) -> MemoryStyle {
+        self.0.style()
+    }
+
+    fn grow(&mut self, delta: Pages) -> Result<Pages, MemoryError> {
+        let result = self.0.grow(delta);
+        if let Err(MemoryError::CouldNotGrow { .. }) = result {
+            self.1.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn grow_at_least(&mut self, min_size: u64) -> Result<(), MemoryError> {
+        self.0.grow_at_least(min_size)
+    }
+
+    fn reset(&mut self) -> Result<(), MemoryError> {
+        self.0.reset()
+    }
+
+    fn vmmemory(&self) -> NonNull<VMMemoryDefinition> {
+        self.0.vmmemory()
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn LinearMemory + 'static>, MemoryError> {
+        Ok(Self::new(self.0.try_clone()?))
+    }
+
+    unsafe fn initialize_with_data(&self, start: usize, data: &[u8]) -> Result<(), Trap> {
+        unsafe { self.0.initialize_with_data(start, data) }
+    }
+
+    fn copy(&mut self) -> Result<Box<dyn LinearMemory + 'static>, MemoryError> {
+        Ok(Self::new(self.0.copy()?))
+    }
+
+    unsafe fn do_wait(
+        &mut self,
+        dst: u32,
+        expected: ExpectedValue,
+        timeout: Option<Duration>,
+    ) -> Result<u32, WaiterError> {
+        unsafe { self.0.do_wait(dst, expected, timeout) }
+    }
+
+    fn do_notify(&mut self, dst: u32, count: u32) -> u32 {
+        self.0.do_notify(dst, count)
+    }
+
+    fn thread_conditions(&self) -> Option<&ThreadConditions> {
+        self.0.thread_conditions()
+    }
+}
+
+impl Drop for LimitedMemory {
+    fn drop(&mut self) {
+        // Another memory may be created at the same address later on.
+        let mut limited_memories = LIMITED_MEMORIES.write();
+        limited_memories.remove(&self.0.vmmemory().as_ptr().addr());
+        LIMITED_MEMORY_COUNT.store(limited_memories.len(), Ordering::Relaxed);
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{make_engine, make_runtime_engine};
-    use crate::module::{CacheKey, ModuleCache, PlruCache};
-    use std::io::Write;
+    use super::{
+        make_cranelift_engine_with_metering, make_cranelift_engine_with_metering_limit,
+        make_engine, make_runtime_engine, CostModel, WASM_METERING_LIMIT,
+    };
+    use crate::module::{
+        content_key, CacheKey, FilesystemBudget, FilesystemModuleStore, InstanceBuilder,
+        ModuleBuilder, ModuleCache, Pruned, SerializedModuleStore,
+    };
+    use crate::prelude::*;
+    use bytes::Bytes;
+    use parking_lot::Mutex;
+    use std::collections::BTreeMap;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::SystemTime;
     use tempfile::TempDir;
     use wasmer::Module;
 
@@ -90,6 +393,15 @@ mod tests {
         ModuleCache::new(make_engine, make_runtime_engine, filesystem_path)
     }
 
+    /// The store that caches built by [`module_cache`] keep their modules in
+    /// under `filesystem_path`.
+    fn fs_store(filesystem_path: &std::path::Path) -> FilesystemModuleStore {
+        FilesystemModuleStore::new(
+            filesystem_path.to_owned(),
+            &ModuleBuilder::new(make_engine, make_runtime_engine),
+        )
+    }
+
     #[test]
     fn cache_save_to_memory_and_fs() {
         // simple example wasm taken from wasmer docs
@@ -104,29 +416,34 @@ mod tests {
         ];
         let tmp_fs_cache_dir = TempDir::new().unwrap();
         let module_cache = module_cache(Some(tmp_fs_cache_dir.path().to_owned()));
-        assert!(module_cache
-            .filesystem_path
-            .clone()
-            .unwrap()
-            .read_dir()
-            .unwrap()
-            .next()
-            .is_none());
-        assert!(module_cache.cache.read().cache.is_empty());
+        assert!(tmp_fs_cache_dir.path().read_dir().unwrap().next().is_none());
+        assert!(module_cache.keys().is_empty());
 
         let key: CacheKey = [0u8; 32];
         let module = module_cache.get(key, &wasm).unwrap();
 
         // make sure module has been stored in the in-memory cache under `key`
-        {
-            let deserialized_cached_module = module_cache.cache.write().get_item(&key).unwrap();
-            assert_eq!(*deserialized_cached_module, *module);
-        }
+        assert!(module_cache.contains(&key));
+        assert_eq!(vec![key], module_cache.keys());
+        let stats = module_cache.stats();
+        assert_eq!(
+            (0, 1, 0, 1),
+            (
+                stats.hits,
+                stats.misses,
+                stats.filesystem_hits,
+                stats.compiles
+            )
+        );
+        assert_eq!(1, stats.modules);
+
+        // the second get is a hit
+        assert!(Arc::ptr_eq(&module, &module_cache.get(key, &wasm).unwrap()));
+        assert_eq!(1, module_cache.stats().hits);
 
         // make sure module has been stored in serialized filesystem cache
         {
-            let serialized_module_path =
-                module_cache.filesystem_path.unwrap().join(hex::encode(key));
+            let serialized_module_path = fs_store(tmp_fs_cache_dir.path()).module_path(&key);
             assert!(std::fs::metadata(serialized_module_path).is_ok());
         }
     }
@@ -144,16 +461,18 @@ mod tests {
             0x70, 0x30,
         ];
         let module_cache = module_cache(None);
-        assert!(module_cache.cache.read().cache.is_empty());
+        assert!(module_cache.keys().is_empty());
 
         let key: CacheKey = [0u8; 32];
         let module = module_cache.get(key, &wasm).unwrap();
 
         // make sure module has been stored in deserialized cache under key
-        {
-            let deserialized_cached_module = module_cache.cache.write().get_item(&key).unwrap();
-            assert_eq!(*deserialized_cached_module, *module);
-        }
+        assert!(module_cache.contains(&key));
+
+        // the second get is a hit
+        assert!(Arc::ptr_eq(&module, &module_cache.get(key, &wasm).unwrap()));
+        let stats = module_cache.stats();
+        assert_eq!((1, 1, 1), (stats.hits, stats.misses, stats.compiles));
     }
 
     #[test]
@@ -176,14 +495,12 @@ mod tests {
         let compiler_engine = make_engine();
         let module =
             std::sync::Arc::new(Module::from_binary(&compiler_engine, wasm.as_slice()).unwrap());
-        let serialized_module = module.serialize().unwrap();
-        let serialized_module_path = tmp_fs_cache_dir.path().join(hex::encode(key));
-        let mut file = std::fs::OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&serialized_module_path)
+        let artifact = ModuleBuilder::new(make_engine, make_runtime_engine)
+            .compile_to_artifact(&wasm)
             .unwrap();
-        file.write_all(&serialized_module).unwrap();
+        let store = fs_store(tmp_fs_cache_dir.path());
+        std::fs::create_dir(store.dir_path()).unwrap();
+        store.put(&key, &artifact).unwrap();
 
         // make sure module can be retrieved from cache
         let module_retreived = module_cache.get(key, &wasm).unwrap();
@@ -191,15 +508,15 @@ mod tests {
             *module_retreived.serialize().unwrap(),
             *module.serialize().unwrap()
         );
+        let stats = module_cache.stats();
+        assert_eq!((1, 0), (stats.filesystem_hits, stats.compiles));
 
         // make sure module is stored in deserialized cache
-        {
-            let deserialized_cached_module = module_cache.cache.write().get_item(&key).unwrap();
-            assert_eq!(
-                *deserialized_cached_module.serialize().unwrap(),
-                *module.serialize().unwrap()
-            );
-        }
+        assert!(Arc::ptr_eq(
+            &module_retreived,
+            &module_cache.get(key, &wasm).unwrap()
+        ));
+        assert_eq!(1, module_cache.stats().hits);
     }
 
     #[test]
@@ -223,36 +540,689 @@ mod tests {
         let key: CacheKey = [0u8; 32];
 
         // Build module, serialize, save directly to filesystem
-        let serialized_module_path = tmp_fs_cache_dir.path().join(hex::encode(key));
-        let mut file = std::fs::OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&serialized_module_path)
-            .unwrap();
-        file.write_all(&bad_serialized_wasm).unwrap();
+        let store = fs_store(tmp_fs_cache_dir.path());
+        std::fs::create_dir(store.dir_path()).unwrap();
+        store.put(&key, &bad_serialized_wasm).unwrap();
 
         // Module can still be retrieved from fs cache, as it has been deleted from the filesystem and re-added to the cache
         let res = module_cache.get(key, &wasm);
         assert!(res.is_ok());
+        let stats = module_cache.stats();
+        assert_eq!(
+            (0, 1, 1),
+            (stats.filesystem_hits, stats.corrupt_files, stats.compiles)
+        );
 
         let compiler_engine = make_engine();
         let module =
             std::sync::Arc::new(Module::from_binary(&compiler_engine, wasm.as_slice()).unwrap());
 
         // make sure module is stored in deserialized cache
-        {
-            let deserialized_cached_module = module_cache.cache.write().get_item(&key).unwrap();
-            assert_eq!(
-                *deserialized_cached_module.serialize().unwrap(),
-                *module.serialize().unwrap()
+        let cached_module = module_cache.get(key, &wasm).unwrap();
+        assert_eq!(1, module_cache.stats().hits);
+        assert_eq!(
+            *cached_module.serialize().unwrap(),
+            *module.serialize().unwrap()
+        );
+
+        // make sure module has been stored in serialized filesystem cache
+        assert!(store.get(&key).unwrap().is_some());
+    }
+
+    #[test]
+    fn cache_get_from_fs_tampered() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+        let tmp_fs_cache_dir = TempDir::new().unwrap();
+        let key: CacheKey = [0u8; 32];
+        let keyed_cache = || {
+            module_cache(Some(tmp_fs_cache_dir.path().to_owned())).integrity_key(b"secret".to_vec())
+        };
+        let serialized_module_path = fs_store(tmp_fs_cache_dir.path()).module_path(&key);
+
+        keyed_cache().get(key, &wasm).unwrap();
+        let artifact = std::fs::read(&serialized_module_path).unwrap();
+
+        // An untouched artifact is loaded from the filesystem.
+        let fresh_cache = keyed_cache();
+        fresh_cache.get(key, &wasm).unwrap();
+        assert_eq!(1, fresh_cache.stats().filesystem_hits);
+
+        // A tampered one is discarded and the module compiled again.
+        let mut tampered = artifact.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        std::fs::write(&serialized_module_path, &tampered).unwrap();
+        let fresh_cache = keyed_cache();
+        fresh_cache.get(key, &wasm).unwrap();
+        let stats = fresh_cache.stats();
+        assert_eq!(
+            (0, 1, 1),
+            (stats.filesystem_hits, stats.corrupt_files, stats.compiles)
+        );
+        assert_eq!(artifact, std::fs::read(&serialized_module_path).unwrap());
+
+        // So is one sealed without the key.
+        let unkeyed_cache = module_cache(Some(tmp_fs_cache_dir.path().to_owned()));
+        unkeyed_cache.get(key, &wasm).unwrap();
+        assert_eq!(1, unkeyed_cache.stats().corrupt_files);
+    }
+
+    #[test]
+    fn cache_get_from_read_only_fs() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+        let tmp_fs_cache_dir = TempDir::new().unwrap();
+        let key: CacheKey = [0u8; 32];
+        module_cache(Some(tmp_fs_cache_dir.path().to_owned()))
+            .get(key, &wasm)
+            .unwrap();
+        let serialized_module_path = fs_store(tmp_fs_cache_dir.path()).module_path(&key);
+        let mut permissions = std::fs::metadata(&serialized_module_path)
+            .unwrap()
+            .permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&serialized_module_path, permissions).unwrap();
+
+        let fresh_cache = module_cache(Some(tmp_fs_cache_dir.path().to_owned()));
+        fresh_cache.get(key, &wasm).unwrap();
+        let stats = fresh_cache.stats();
+        assert_eq!(
+            (1, 0, 0),
+            (stats.filesystem_hits, stats.corrupt_files, stats.compiles)
+        );
+    }
+
+    #[test]
+    fn cache_remove_and_clear() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+        let tmp_fs_cache_dir = TempDir::new().unwrap();
+        let module_cache = module_cache(Some(tmp_fs_cache_dir.path().to_owned()));
+        let dir_path = fs_store(tmp_fs_cache_dir.path()).dir_path().to_owned();
+        std::fs::create_dir(&dir_path).unwrap();
+        let other_path = dir_path.join("other");
+        std::fs::write(&other_path, b"not a module").unwrap();
+        let fs_keys = || {
+            dir_path
+                .read_dir()
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path() != other_path)
+                .count()
+        };
+
+        // A module from before modules were kept apart by fingerprint.
+        let legacy_path = tmp_fs_cache_dir.path().join(hex::encode([9; 32]));
+        std::fs::write(&legacy_path, b"").unwrap();
+
+        let keys: Vec<CacheKey> = (0..3).map(|i| [i; 32]).collect();
+        for key in &keys {
+            module_cache.get(*key, &wasm).unwrap();
+        }
+        assert_eq!(3, fs_keys());
+
+        module_cache.remove(keys[0]).unwrap();
+        assert!(!module_cache.contains(&keys[0]));
+        assert!(module_cache.contains(&keys[1]));
+        assert_eq!(2, fs_keys());
+        // Removing again is fine.
+        module_cache.remove(keys[0]).unwrap();
+
+        module_cache.clear();
+        assert!(module_cache.keys().is_empty());
+        assert_eq!(2, fs_keys());
+
+        module_cache.clear_filesystem().unwrap();
+        assert_eq!(0, fs_keys());
+        assert!(other_path.exists());
+        assert!(!legacy_path.exists());
+
+        // The freed up slots can be used again.
+        module_cache.get(keys[0], &wasm).unwrap();
+        assert_eq!(vec![keys[0]], module_cache.keys());
+    }
+
+    #[test]
+    fn cache_fs_write_error() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+        let tmp_fs_cache_dir = TempDir::new().unwrap();
+        let missing_dir = tmp_fs_cache_dir.path().join("missing");
+        let module_cache = module_cache(Some(missing_dir.clone()));
+
+        // The module is still cached in memory.
+        module_cache.get([0; 32], &wasm).unwrap();
+        assert!(module_cache.contains(&[0; 32]));
+        assert_eq!(1, module_cache.stats().filesystem_write_errors);
+
+        // Successful writes leave no temporary files behind.
+        std::fs::create_dir(&missing_dir).unwrap();
+        module_cache.get([1; 32], &wasm).unwrap();
+        let files: Vec<_> = fs_store(&missing_dir)
+            .dir_path()
+            .read_dir()
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(vec![std::ffi::OsString::from(hex::encode([1; 32]))], files);
+        assert_eq!(1, module_cache.stats().filesystem_write_errors);
+    }
+
+    #[test]
+    fn prune_missing_directory() {
+        // E.g. another cache removed it while pruning.
+        let tmp_fs_cache_dir = TempDir::new().unwrap();
+        let store = fs_store(&tmp_fs_cache_dir.path().join("gone")).budget(FilesystemBudget {
+            max_size: Some(0),
+            max_age: None,
+        });
+        assert_eq!(Pruned::default(), store.prune().unwrap());
+    }
+
+    #[test]
+    fn cache_prune() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+        let tmp_fs_cache_dir = TempDir::new().unwrap();
+        let filesystem_path = tmp_fs_cache_dir.path().to_owned();
+        let module_cache = module_cache(Some(filesystem_path.clone()));
+        let hours_ago = |hours: u64| SystemTime::now() - Duration::from_secs(hours * 60 * 60);
+        let set_modified = |path: &std::path::Path, modified| {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+
+        let keys: Vec<CacheKey> = (0..3).map(|i| [i; 32]).collect();
+        for (key, hours) in keys.iter().zip([3, 2, 1]) {
+            module_cache.get(*key, &wasm).unwrap();
+            set_modified(
+                &fs_store(&filesystem_path).module_path(key),
+                hours_ago(hours),
             );
         }
+        let size = std::fs::metadata(fs_store(&filesystem_path).module_path(&keys[0]))
+            .unwrap()
+            .len();
+        let dir_path = fs_store(&filesystem_path).dir_path().to_owned();
+        let temp_path = dir_path.join(".interrupted.tmp");
+        std::fs::write(&temp_path, b"").unwrap();
+        set_modified(&temp_path, hours_ago(2));
+        let other_dir_path = filesystem_path.join(hex::encode([0; 8]));
+        std::fs::create_dir(&other_dir_path).unwrap();
+        let other_path = other_dir_path.join(hex::encode(keys[0]));
+        std::fs::write(&other_path, b"").unwrap();
+        set_modified(&other_path, hours_ago(48));
+        // A module from before modules were kept apart by fingerprint.
+        let legacy_path = filesystem_path.join(hex::encode(keys[1]));
+        std::fs::write(&legacy_path, b"").unwrap();
+        set_modified(&legacy_path, hours_ago(72));
 
-        // make sure module has been stored in serialized filesystem cache
+        // Without a budget only garbage goes.
+        assert_eq!(Pruned { files: 1, bytes: 0 }, module_cache.prune().unwrap());
+        assert!(!temp_path.exists());
+
+        let module_cache = ModuleCache::new(
+            make_engine,
+            make_runtime_engine,
+            Some(filesystem_path.clone()),
+        )
+        .filesystem_budget(FilesystemBudget {
+            max_size: Some(2 * size),
+            max_age: Some(Duration::from_secs(24 * 60 * 60)),
+        });
+        assert_eq!(
+            Pruned {
+                files: 3,
+                bytes: size
+            },
+            module_cache.prune().unwrap()
+        );
+        assert!(!other_dir_path.exists());
+        assert!(!legacy_path.exists());
+        let mut remaining: Vec<_> = dir_path
+            .read_dir()
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        remaining.sort();
+        assert_eq!(
+            vec![
+                std::ffi::OsString::from(hex::encode(keys[1])),
+                std::ffi::OsString::from(hex::encode(keys[2]))
+            ],
+            remaining
+        );
+
+        // Loading a module from the filesystem makes it the most recently
+        // used, so writing another module prunes the one after it.
+        module_cache.get(keys[1], &wasm).unwrap();
+        module_cache.get(keys[0], &wasm).unwrap();
+        assert!(fs_store(&filesystem_path).module_path(&keys[1]).exists());
+        assert!(!fs_store(&filesystem_path).module_path(&keys[2]).exists());
+    }
+
+    #[test]
+    fn cache_prune_throttled() {
+        let tmp_fs_cache_dir = TempDir::new().unwrap();
+        let store = fs_store(tmp_fs_cache_dir.path()).budget(FilesystemBudget {
+            max_size: Some(100),
+            max_age: None,
+        });
+        // The first write prunes to find out how much is there.
+        store.put(&[0; 32], &[0; 40]).unwrap();
+        let temp_path = store.dir_path().join(".interrupted.tmp");
+        std::fs::write(&temp_path, b"").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&temp_path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))
+            .unwrap();
+
+        // Writes that stay within the budget don't prune.
+        store.put(&[1; 32], &[1; 40]).unwrap();
+        assert!(temp_path.exists());
+        store.put(&[2; 32], &[2; 40]).unwrap();
+        assert!(!temp_path.exists());
+        assert!(!store.module_path(&[0; 32]).exists());
+        assert!(store.module_path(&[2; 32]).exists());
+    }
+
+    #[test]
+    fn builder_fingerprint_cost_model() {
+        let fingerprint =
+            |make_engine| ModuleBuilder::new(make_engine, make_runtime_engine).fingerprint();
+        let flat = fingerprint(|| {
+            make_cranelift_engine_with_metering(WASM_METERING_LIMIT, CostModel::flat())
+        });
+        // Changing the weight of any class changes the fingerprint.
+        for make_engine in [
+            || {
+                let cost_model = CostModel {
+                    arithmetic: 2,
+                    ..CostModel::flat()
+                };
+                make_cranelift_engine_with_metering(WASM_METERING_LIMIT, cost_model)
+            },
+            || {
+                let cost_model = CostModel {
+                    memory: 2,
+                    ..CostModel::flat()
+                };
+                make_cranelift_engine_with_metering(WASM_METERING_LIMIT, cost_model)
+            },
+            || {
+                let cost_model = CostModel {
+                    memory_grow: 2,
+                    ..CostModel::flat()
+                };
+                make_cranelift_engine_with_metering(WASM_METERING_LIMIT, cost_model)
+            },
+            || {
+                let cost_model = CostModel {
+                    control_flow: 2,
+                    ..CostModel::flat()
+                };
+                make_cranelift_engine_with_metering(WASM_METERING_LIMIT, cost_model)
+            },
+            || {
+                let cost_model = CostModel {
+                    call: 2,
+                    ..CostModel::flat()
+                };
+                make_cranelift_engine_with_metering(WASM_METERING_LIMIT, cost_model)
+            },
+            || {
+                let cost_model = CostModel {
+                    bulk_memory: 2,
+                    ..CostModel::flat()
+                };
+                make_cranelift_engine_with_metering(WASM_METERING_LIMIT, cost_model)
+            },
+        ] as [fn() -> wasmer::Engine; 6]
         {
-            let serialized_module_path =
-                module_cache.filesystem_path.unwrap().join(hex::encode(key));
-            assert!(std::fs::metadata(serialized_module_path).is_ok());
+            assert_ne!(flat, fingerprint(make_engine));
+        }
+    }
+
+    #[test]
+    fn builder_fingerprint() {
+        let fingerprint =
+            |make_engine| ModuleBuilder::new(make_engine, make_runtime_engine).fingerprint();
+        let metered = fingerprint(make_engine);
+        assert_eq!(metered, fingerprint(make_engine));
+        assert_ne!(
+            metered,
+            fingerprint(|| make_cranelift_engine_with_metering_limit(1))
+        );
+        assert_ne!(metered, fingerprint(wasmer::Engine::default));
+
+        // Caches with different builders keep their modules apart.
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+        let tmp_fs_cache_dir = TempDir::new().unwrap();
+        module_cache(Some(tmp_fs_cache_dir.path().to_owned()))
+            .get([0; 32], &wasm)
+            .unwrap();
+        let other_cache = ModuleCache::new(
+            wasmer::Engine::default,
+            make_runtime_engine,
+            Some(tmp_fs_cache_dir.path().to_owned()),
+        );
+        other_cache.get([0; 32], &wasm).unwrap();
+        let stats = other_cache.stats();
+        assert_eq!((0, 1), (stats.filesystem_hits, stats.compiles));
+        assert_eq!(2, tmp_fs_cache_dir.path().read_dir().unwrap().count());
+    }
+
+    #[test]
+    fn cache_concurrent_gets() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+        let tmp_fs_cache_dir = TempDir::new().unwrap();
+        let module_cache = module_cache(Some(tmp_fs_cache_dir.path().to_owned()));
+        let key: CacheKey = [0u8; 32];
+        let barrier = std::sync::Barrier::new(8);
+
+        let modules: Vec<Arc<Module>> = std::thread::scope(|scope| {
+            let calls: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        module_cache.get(key, &wasm).unwrap()
+                    })
+                })
+                .collect();
+            calls.into_iter().map(|call| call.join().unwrap()).collect()
+        });
+
+        // Everyone got the one module that was compiled.
+        assert!(modules
+            .iter()
+            .all(|module| Arc::ptr_eq(module, &modules[0])));
+        let stats = module_cache.stats();
+        assert_eq!((1, 0), (stats.compiles, stats.filesystem_write_errors));
+
+        // Nothing is left over from the concurrent gets, a removed module is
+        // compiled again.
+        module_cache.remove(key).unwrap();
+        assert!(!Arc::ptr_eq(
+            &modules[0],
+            &module_cache.get(key, &wasm).unwrap()
+        ));
+        assert_eq!(2, module_cache.stats().compiles);
+    }
+
+    #[test]
+    fn cache_precompile() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap().to_vec();
+        let tmp_fs_cache_dir = TempDir::new().unwrap();
+        let module_cache =
+            Arc::new(module_cache(Some(tmp_fs_cache_dir.path().to_owned())).precompile_threads(2));
+        let keys: Vec<CacheKey> = (0..3).map(|i| [i; 32]).collect();
+
+        let handles = module_cache.warm(keys.iter().map(|key| (*key, wasm.clone())));
+        for (key, handle) in keys.iter().zip(&handles) {
+            let module = handle.wait().unwrap();
+            assert_eq!(*key, handle.key());
+            assert!(handle.is_finished());
+            assert!(Arc::ptr_eq(
+                &module,
+                &module_cache.get(*key, &wasm).unwrap()
+            ));
+            assert!(fs_store(tmp_fs_cache_dir.path()).module_path(key).exists());
+        }
+        let stats = module_cache.stats();
+        assert_eq!((3, 3), (stats.compiles, stats.hits));
+
+        // Handles are futures too, and failures end up in them.
+        let mut handle = module_cache.precompile([3; 32], b"not wasm".to_vec());
+        handle.wait().unwrap_err();
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        assert!(matches!(
+            std::pin::Pin::new(&mut handle).poll(&mut cx),
+            std::task::Poll::Ready(Err(_))
+        ));
+        assert!(!module_cache.contains(&[3; 32]));
+    }
+
+    #[test]
+    fn cache_import_artifact() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+        let artifact = ModuleBuilder::new(make_engine, make_runtime_engine)
+            .compile_to_artifact(&wasm)
+            .unwrap();
+        let tmp_fs_cache_dir = TempDir::new().unwrap();
+        let cache = module_cache(Some(tmp_fs_cache_dir.path().to_owned()));
+        let key: CacheKey = [0u8; 32];
+
+        cache.import_artifact(key, artifact.clone()).unwrap();
+        assert!(cache.contains(&key));
+        assert!(fs_store(tmp_fs_cache_dir.path()).module_path(&key).exists());
+
+        // Neither this cache nor the next one compiles the module.
+        cache.get(key, &wasm).unwrap();
+        let fresh_cache = module_cache(Some(tmp_fs_cache_dir.path().to_owned()));
+        fresh_cache.get(key, &wasm).unwrap();
+        assert_eq!(0, cache.stats().compiles);
+        assert_eq!(
+            (1, 0),
+            (
+                fresh_cache.stats().filesystem_hits,
+                fresh_cache.stats().compiles
+            )
+        );
+
+        // Damaged artifacts are refused.
+        let mut damaged = artifact.to_vec();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert!(cache.import_artifact([1; 32], damaged.into()).is_err());
+        assert!(cache
+            .import_artifact([1; 32], Bytes::from_static(b"not an artifact"))
+            .is_err());
+        // So are artifacts built without this cache's metering.
+        let unmetered = ModuleBuilder::new(wasmer::Engine::default, make_runtime_engine)
+            .compile_to_artifact(&wasm)
+            .unwrap();
+        assert!(cache.import_artifact([1; 32], unmetered).is_err());
+        assert!(!cache.contains(&[1; 32]));
+    }
+
+    #[test]
+    fn cache_get_by_content() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+        let module_cache = module_cache(None);
+
+        let module = module_cache.get_by_content(&wasm).unwrap();
+        assert_eq!(vec![content_key(&wasm)], module_cache.keys());
+        assert!(Arc::ptr_eq(
+            &module,
+            &module_cache.get(content_key(&wasm), &wasm).unwrap()
+        ));
+        assert_eq!(1, module_cache.stats().compiles);
+    }
+
+    #[test]
+    fn cache_in_memory_modules_run_metered() {
+        let module_cache = ModuleCache::new(
+            || make_cranelift_engine_with_metering_limit(1_234),
+            make_runtime_engine,
+            None,
+        );
+
+        // Every module gets its own metering even though none of them are
+        // round tripped through serialization.
+        let instances: Vec<_> = (0..2)
+            .map(|i| {
+                let wat = format!(
+                    r#"(module
+                            (memory (export "memory") 1)
+                            (func (export "__hc__allocate_1") (param i32) (result i32) i32.const 0)
+                            (func (export "__hc__deallocate_1") (param i32 i32))
+                            (func (export "add") (param i32) (result i32)
+                                local.get 0
+                                i32.const {i}
+                                i32.add))"#
+                );
+                let wasm = wasmer::wat2wasm(wat.as_bytes()).unwrap();
+                let module = module_cache.get([i; 32], &wasm).unwrap();
+                InstanceBuilder::new(module, make_runtime_engine)
+                    .metered(true)
+                    .build(|_, _| wasmer::Imports::new())
+                    .unwrap()
+            })
+            .collect();
+
+        for (i, instance_with_store) in instances.iter().enumerate() {
+            let mut store = instance_with_store.store.lock();
+            let add = instance_with_store
+                .instance
+                .exports
+                .get_typed_function::<i32, i32>(&*store, "add")
+                .unwrap();
+            assert_eq!(1 + i as i32, add.call(&mut *store, 1).unwrap());
+            let remaining_points = instance_with_store
+                .instance
+                .exports
+                .get_global("wasmer_metering_remaining_points")
+                .unwrap()
+                .get(&mut *store)
+                .unwrap_i64();
+            assert!(remaining_points < 1_234);
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct MapStore(Mutex<BTreeMap<CacheKey, Bytes>>);
+
+    impl SerializedModuleStore for MapStore {
+        fn get(&self, key: &CacheKey) -> Result<Option<Bytes>, std::io::Error> {
+            Ok(self.0.lock().get(key).cloned())
+        }
+
+        fn put(&self, key: &CacheKey, bytes: &[u8]) -> Result<(), std::io::Error> {
+            self.0.lock().insert(*key, Bytes::copy_from_slice(bytes));
+            Ok(())
         }
+
+        fn remove(&self, key: &CacheKey) -> Result<(), std::io::Error> {
+            self.0.lock().remove(key);
+            Ok(())
+        }
+
+        fn list(&self) -> Result<Vec<CacheKey>, std::io::Error> {
+            Ok(self.0.lock().keys().copied().collect())
+        }
+    }
+
+    #[test]
+    fn cache_custom_store() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+        let store = Arc::new(MapStore::default());
+        let store_cache = || module_cache(None).serialized_module_store(store.clone());
+        let keys: Vec<CacheKey> = (0..2).map(|i| [i; 32]).collect();
+
+        for key in &keys {
+            store_cache().get(*key, &wasm).unwrap();
+        }
+        assert_eq!(keys, store.list().unwrap());
+
+        // Another cache finds the modules in the store.
+        let module_cache = store_cache();
+        module_cache.get(keys[0], &wasm).unwrap();
+        let stats = module_cache.stats();
+        assert_eq!((1, 0), (stats.filesystem_hits, stats.compiles));
+
+        // Corrupt modules are removed from the store like from the filesystem.
+        store.put(&keys[1], b"corrupt").unwrap();
+        module_cache.get(keys[1], &wasm).unwrap();
+        assert_eq!(1, module_cache.stats().corrupt_files);
+        assert_ne!(
+            Some(Bytes::from_static(b"corrupt")),
+            store.get(&keys[1]).unwrap()
+        );
+
+        module_cache.remove(keys[0]).unwrap();
+        assert_eq!(vec![keys[1]], store.list().unwrap());
+        module_cache.clear_filesystem().unwrap();
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn instance_builder_missing_export() {
+        // Exports memory and the allocator but none of the metering globals,
+        // because it is compiled without the metering middleware.
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (memory (export "memory") 1)
+                (func (export "__hc__allocate_1") (param i32) (result i32) i32.const 0)
+                (func (export "__hc__deallocate_1") (param i32 i32)))"#,
+        )
+        .unwrap();
+        let module = std::sync::Arc::new(Module::new(&wasmer::Engine::default(), wasm).unwrap());
+
+        assert!(InstanceBuilder::new(module.clone(), make_runtime_engine)
+            .build(|_, _| wasmer::Imports::new())
+            .is_ok());
+
+        let err = InstanceBuilder::new(module, make_runtime_engine)
+            .metered(true)
+            .build(|_, _| wasmer::Imports::new())
+            .unwrap_err();
+        assert_eq!(
+            WasmErrorInner::MissingExport("wasmer_metering_points_exhausted".to_string()),
+            err.downcast::<WasmError>().unwrap().error,
+        );
+    }
+
+    #[test]
+    fn engine_with_metering_limit() {
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (memory (export "memory") 1)
+                (func (export "__hc__allocate_1") (param i32) (result i32) i32.const 0)
+                (func (export "__hc__deallocate_1") (param i32 i32)))"#,
+        )
+        .unwrap();
+        let module_cache = ModuleCache::new(
+            || make_cranelift_engine_with_metering_limit(1_234),
+            make_runtime_engine,
+            None,
+        );
+        let module = module_cache.get([0u8; 32], &wasm).unwrap();
+
+        let instance_with_store = InstanceBuilder::new(module, make_runtime_engine)
+            .metered(true)
+            .build(|_, _| wasmer::Imports::new())
+            .unwrap();
+        let remaining_points = instance_with_store
+            .instance
+            .exports
+            .get_global("wasmer_metering_remaining_points")
+            .unwrap()
+            .get(&mut *instance_with_store.store.lock())
+            .unwrap_i64();
+        assert_eq!(1_234, remaining_points);
+    }
+
+    #[test]
+    fn kept_engines_outlive_modules() {
+        let builder = ModuleBuilder::new(make_engine, make_runtime_engine);
+        let wasm = |i: i32| {
+            wasmer::wat2wasm(
+                format!(r#"(module (func (export "f") (result i32) i32.const {i}))"#).as_bytes(),
+            )
+            .unwrap()
+            .to_vec()
+        };
+
+        // Instances don't hold on to the `Arc` of their module, as e.g. a
+        // module evicted from a cache would show.
+        let module = builder.from_binary_keeping_engine(&wasm(0)).unwrap();
+        let mut store = wasmer::Store::new(make_runtime_engine());
+        let instance = wasmer::Instance::new(&mut store, &module, &wasmer::imports! {}).unwrap();
+        drop(module);
+        for i in 1..10 {
+            builder.from_binary_keeping_engine(&wasm(i)).unwrap();
+        }
+
+        let f = instance.exports.get_function("f").unwrap();
+        assert_eq!(wasmer::Value::I32(0), f.call(&mut store, &[]).unwrap()[0]);
     }
 }
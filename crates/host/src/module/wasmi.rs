@@ -1,3 +1,6 @@
+use crate::module::inject_metering;
+use crate::module::CostModel;
+use crate::module::WASM_METERING_LIMIT;
 use crate::prelude::*;
 use std::sync::Arc;
 use std::sync::OnceLock;
@@ -18,7 +21,12 @@ fn shared_engine() -> &'static Engine {
 
 /// Returns the shared engine that drives the pure-Rust `wasmi` interpreter.
 ///
-/// The interpreter does not support metering.
+/// The interpreter can't add metering while building modules, so metering is
+/// added to the wasm up front instead, see [`meter`].
+///
+/// Stores of this engine can't limit memory either, wasmer doesn't expose the
+/// interpreter's store limits. Use [`crate::module::inject_memory_limit`] to
+/// cap the memory in the wasm instead.
 pub fn make_engine() -> Engine {
     shared_engine().clone()
 }
@@ -42,6 +50,40 @@ pub fn build_module(wasm: &[u8]) -> Result<Arc<Module>, wasmer::RuntimeError> {
     Ok(Arc::new(module))
 }
 
+/// Add metering to wasm before it is built for the interpreter.
+///
+/// Instances start with [`WASM_METERING_LIMIT`] points and are charged 1
+/// point per operator, the same as on the sys backend, see
+/// [`CostModel::flat`]. Pass this to
+/// [`crate::module::ModuleBuilder::instrument`] to meter every module a
+/// builder or cache builds.
+pub fn meter(wasm: &[u8]) -> Result<Vec<u8>, wasmer::RuntimeError> {
+    meter_with_metering(wasm, WASM_METERING_LIMIT, CostModel::flat())
+}
+
+/// Same as [`meter`] but instances start with `metering_limit` points.
+pub fn meter_with_metering_limit(
+    wasm: &[u8],
+    metering_limit: u64,
+) -> Result<Vec<u8>, wasmer::RuntimeError> {
+    meter_with_metering(wasm, metering_limit, CostModel::flat())
+}
+
+/// Same as [`meter`] but with full control over the metering limit and the
+/// cost of each operator.
+pub fn meter_with_metering(
+    wasm: &[u8],
+    metering_limit: u64,
+    cost_model: CostModel,
+) -> Result<Vec<u8>, wasmer::RuntimeError> {
+    inject_metering(wasm, metering_limit, cost_model)
+}
+
+/// Build a metered interpreter module from wasm bytes, see [`meter`].
+pub fn build_metered_module(wasm: &[u8]) -> Result<Arc<Module>, wasmer::RuntimeError> {
+    build_module(&meter(wasm)?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::build_module;
@@ -1,6 +1,8 @@
 pub use crate::env::Env;
 pub use crate::error::*;
 pub use crate::guest;
+pub use crate::metering::Metering;
+pub use crate::metering::MeteringPoints;
 pub use crate::wasm_host_error as wasm_error;
 pub use holochain_serialized_bytes::prelude::*;
 pub use holochain_wasmer_common::result::WasmError;
@@ -0,0 +1,163 @@
+use parking_lot::Condvar;
+use parking_lot::Mutex;
+use parking_lot::MutexGuard;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// How long a thread waits for another job before it stops.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Threads that run jobs in the background, such as guest calls with a
+/// deadline and modules being precompiled.
+///
+/// Threads are started as jobs come in, up to `max_threads`, and jobs beyond
+/// that wait for a thread to be done. Idle threads stop after
+/// [`IDLE_TIMEOUT`], and all of them stop once the `Workers` is dropped and
+/// the jobs queued by then are done.
+pub(crate) struct Workers {
+    name: &'static str,
+    max_threads: usize,
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    jobs: VecDeque<Job>,
+    threads: usize,
+    // Threads waiting for a job.
+    idle: usize,
+    closed: bool,
+}
+
+impl std::fmt::Debug for Workers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.shared.state.lock();
+        f.debug_struct("Workers")
+            .field("name", &self.name)
+            .field("max_threads", &self.max_threads)
+            .field("threads", &state.threads)
+            .field("queued", &state.jobs.len())
+            .finish()
+    }
+}
+
+impl Workers {
+    /// Threads named `name`, at most `max_threads` of them at a time.
+    pub(crate) fn new(name: &'static str, max_threads: usize) -> Self {
+        Self {
+            name,
+            max_threads: max_threads.max(1),
+            shared: Arc::default(),
+        }
+    }
+
+    /// Run `job` on an idle thread, or on a new one if none is idle and there
+    /// are fewer than `max_threads`, or else once a thread is done.
+    ///
+    /// Fails if a new thread is needed but can't be started, in which case
+    /// `job` is dropped without running.
+    pub(crate) fn run(&self, job: impl FnOnce() + Send + 'static) -> Result<(), std::io::Error> {
+        let mut state = self.shared.state.lock();
+        state.jobs.push_back(Box::new(job));
+        if state.idle >= state.jobs.len() || state.threads >= self.max_threads {
+            self.shared.available.notify_one();
+            return Ok(());
+        }
+        let shared = self.shared.clone();
+        match std::thread::Builder::new()
+            .name(self.name.to_string())
+            .spawn(move || work(&shared))
+        {
+            Ok(_) => {
+                state.threads += 1;
+                Ok(())
+            }
+            Err(e) => {
+                state.jobs.pop_back();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.shared.state.lock().closed = true;
+        self.shared.available.notify_all();
+    }
+}
+
+fn work(shared: &Shared) {
+    let mut state = shared.state.lock();
+    loop {
+        if let Some(job) = state.jobs.pop_front() {
+            MutexGuard::unlocked(&mut state, || run_job(job));
+            continue;
+        }
+        if state.closed {
+            break;
+        }
+        state.idle += 1;
+        let timed_out = shared
+            .available
+            .wait_for(&mut state, IDLE_TIMEOUT)
+            .timed_out();
+        state.idle -= 1;
+        // A job may have come in just as the wait timed out.
+        if timed_out && state.jobs.is_empty() {
+            break;
+        }
+    }
+    state.threads -= 1;
+}
+
+/// A panicking job drops whatever it was going to send its result with, which
+/// is how the caller finds out, so the thread can carry on.
+fn run_job(job: Job) {
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Workers;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn runs_queued_jobs_on_few_threads() {
+        let workers = Workers::new("test-workers", 2);
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..10 {
+            let sender = sender.clone();
+            workers
+                .run(move || {
+                    std::thread::sleep(Duration::from_millis(5));
+                    sender.send(i).unwrap();
+                })
+                .unwrap();
+        }
+        assert!(workers.shared.state.lock().threads <= 2);
+        let mut done: Vec<i32> = receiver.iter().take(10).collect();
+        done.sort();
+        assert_eq!((0..10).collect::<Vec<_>>(), done);
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let workers = Workers::new("test-workers", 1);
+        workers.run(|| panic!("job panicked")).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        workers.run(move || sender.send(()).unwrap()).unwrap();
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(1, workers.shared.state.lock().threads);
+    }
+}
@@ -20,3 +20,6 @@ cargo test --no-default-features --features error-as-host,wasmer-sys-cranelift $
 
 # build wasm and run the "full" tests for wasmer-sys-cranelift
 cargo test --release -p tests --no-default-features --features wasmer-sys-cranelift ${1-} -- --nocapture
+
+# and again with async calls
+cargo test --release -p tests --no-default-features --features wasmer-sys-cranelift,async ${1-} -- --nocapture
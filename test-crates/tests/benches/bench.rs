@@ -1,8 +1,15 @@
+use criterion::BatchSize;
 use criterion::BenchmarkId;
 use criterion::Throughput;
 use criterion::{criterion_group, criterion_main, Criterion};
+#[cfg(feature = "wasmer-sys")]
+use holochain_wasmer_host::module::sys::{make_engine, make_runtime_engine};
+#[cfg(all(feature = "wasmer-wasmi", not(feature = "wasmer-sys")))]
+use holochain_wasmer_host::module::wasmi::{make_engine, make_runtime_engine};
+use holochain_wasmer_host::module::{CacheKey, ModuleCache, SerializedModuleStore};
 use holochain_wasmer_host::prelude::*;
 use rand::{prelude::*, rng};
+use std::sync::Arc;
 use tempfile::TempDir;
 use test::wasms::TestWasm;
 use wasmer::AsStoreMut;
@@ -52,6 +59,66 @@ pub fn wasm_module_deserialize_from_file(c: &mut Criterion) {
     }
 }
 
+/// Keeps nothing, so that a cache with this store still round trips every
+/// module it compiles through serialization but never touches the filesystem.
+#[derive(Debug)]
+struct NullStore;
+
+impl SerializedModuleStore for NullStore {
+    fn get(&self, _key: &CacheKey) -> Result<Option<bytes::Bytes>, std::io::Error> {
+        Ok(None)
+    }
+
+    fn put(&self, _key: &CacheKey, _bytes: &[u8]) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn remove(&self, _key: &CacheKey) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<CacheKey>, std::io::Error> {
+        Ok(Vec::new())
+    }
+}
+
+/// get a module that isn't cached yet, from a metered cache that keeps
+/// serialized modules and from one that only keeps them in memory and so
+/// skips the serialization round trip
+pub fn wasm_module_cache_miss(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wasm_module_cache_miss");
+
+    for wasm in [
+        TestWasm::Empty,
+        TestWasm::Io,
+        TestWasm::Core,
+        TestWasm::Memory,
+    ] {
+        for (name, with_store) in [("round_trip", true), ("in_memory", false)] {
+            group.bench_function(BenchmarkId::new(name, wasm.name()), |b| {
+                b.iter_batched(
+                    || {
+                        let cache = ModuleCache::new(make_engine, make_runtime_engine, None);
+                        if with_store {
+                            cache.serialized_module_store(Arc::new(NullStore))
+                        } else {
+                            cache
+                        }
+                    },
+                    |cache| {
+                        cache.get(wasm.key(), wasm.bytes()).unwrap();
+                        // Dropped outside of the measurement.
+                        cache
+                    },
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+
+    group.finish()
+}
+
 /// create a module
 pub fn wasm_module(c: &mut Criterion) {
     let mut group = c.benchmark_group("wasm_module");
@@ -276,6 +343,7 @@ criterion_group!(
     benches,
     wasm_module_compile,
     wasm_module_deserialize_from_file,
+    wasm_module_cache_miss,
     // currently the bench fails because such numerous deserialization of modules causes memory leaks
     // because of an upstream issue where the memory for deserialization is kept as long as the engine lives
     // https://github.com/wasmerio/wasmer/issues/4377#issuecomment-1879386384
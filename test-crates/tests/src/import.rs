@@ -59,3 +59,16 @@ pub fn imports(store: &mut StoreMut, function_env: &FunctionEnv<Env>) -> Imports
         },
     }
 }
+
+/// Same as [`imports`] but processing strings is done by an async host
+/// function.
+#[cfg(feature = "async")]
+pub fn imports_async(store: &mut StoreMut, function_env: &FunctionEnv<Env>) -> Imports {
+    let mut imports = imports(store, function_env);
+    imports.define(
+        "env",
+        "__hc__test_process_string_2",
+        Function::new_typed_with_env_async(store, function_env, crate::test_process_string_async),
+    );
+    imports
+}
@@ -5,8 +5,6 @@ use holochain_wasmer_host::prelude::*;
 use holochain_wasmer_host::wasm_host_error as wasm_error;
 use test_common::SomeStruct;
 use wasmer::FunctionEnvMut;
-#[cfg(feature = "wasmer-sys")]
-use wasmer_middlewares::metering::MeteringPoints;
 
 pub fn short_circuit(
     _env: FunctionEnvMut<Env>,
@@ -30,6 +28,38 @@ pub fn test_process_string(
     env.move_data_to_guest(&mut store_mut, Ok::<String, WasmError>(processed_string))
 }
 
+/// Same as [`test_process_string`] but suspends the guest while processing.
+#[cfg(feature = "async")]
+pub async fn test_process_string_async(
+    function_env: wasmer::AsyncFunctionEnvMut<Env>,
+    guest_ptr: GuestPtr,
+    len: Len,
+) -> Result<u64, wasmer::RuntimeError> {
+    let string: String = Env::with_async(&function_env, |env, store_mut| {
+        env.consume_bytes_from_guest(store_mut, guest_ptr, len)
+    })
+    .await?;
+
+    // Give up control once, as though waiting on IO.
+    let mut yielded = false;
+    std::future::poll_fn(|cx| {
+        if yielded {
+            std::task::Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        }
+    })
+    .await;
+
+    let processed_string = format!("host: {}", string);
+    Env::with_async(&function_env, |env, store_mut| {
+        env.move_data_to_guest(store_mut, Ok::<String, WasmError>(processed_string))
+    })
+    .await
+}
+
 pub fn test_process_struct(
     mut function_env: FunctionEnvMut<Env>,
     guest_ptr: GuestPtr,
@@ -52,7 +82,6 @@ pub fn debug(
     Ok(())
 }
 
-#[cfg(feature = "wasmer-sys")]
 pub fn decrease_points(
     mut function_env: FunctionEnvMut<Env>,
     guest_ptr: GuestPtr,
@@ -78,20 +107,6 @@ pub fn decrease_points(
     )
 }
 
-// Stub used when only the wasmi backend is compiled in. With the sys backend
-// also enabled the real metered version above takes precedence.
-#[cfg(all(feature = "wasmer-wasmi", not(feature = "wasmer-sys")))]
-pub fn decrease_points(
-    _function_env: FunctionEnvMut<Env>,
-    _guest_ptr: GuestPtr,
-    _len: Len,
-) -> Result<u64, wasmer::RuntimeError> {
-    Err(wasm_error!(WasmErrorInner::Guest(
-        "Metering is not supported with the wasmer wasmi backend".into()
-    ))
-    .into())
-}
-
 pub fn err(_: FunctionEnvMut<Env>) -> Result<(), wasmer::RuntimeError> {
     Err(wasm_error!(WasmErrorInner::Guest("oh no!".into())).into())
 }
@@ -150,21 +165,22 @@ pub mod tests {
         let module = (*TestWasm::Core.module(false)).clone();
         // Imports will be the minimal set of functions actually used by the wasm
         // NOT the complete list defined by `host_externs!`.
-        assert_eq!(
-            vec![
-                "__hc__short_circuit_5".to_string(),
-                "__hc__test_process_string_2".to_string(),
-                "__hc__test_process_struct_2".to_string(),
-                "__hc__decrease_points_1".to_string(),
-                "__hc__call_ping_1".to_string(),
-            ]
-            .sort(),
-            module
-                .imports()
-                .map(|import| import.name().to_string())
-                .collect::<Vec<String>>()
-                .sort()
-        );
+        let mut expected = vec![
+            "__hc__short_circuit_5".to_string(),
+            "__hc__test_process_string_2".to_string(),
+            "__hc__test_process_struct_2".to_string(),
+            "__hc__decrease_points_1".to_string(),
+            "__hc__call_ping_1".to_string(),
+            // Added by `ModuleBuilder::interruptible`.
+            "__hc__interrupt_1".to_string(),
+        ];
+        expected.sort();
+        let mut imports = module
+            .imports()
+            .map(|import| import.name().to_string())
+            .collect::<Vec<String>>();
+        imports.sort();
+        assert_eq!(expected, imports);
     }
 
     // Reinstate this test when metering is working.
@@ -183,6 +199,156 @@ pub mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn loop_forever_out_of_points() {
+        let InstanceWithStore { store, instance } = TestWasm::Core.instance();
+        guest::set_remaining_points(&mut store.lock().as_store_mut(), &instance, 1_000_000)
+            .unwrap();
+        let result: Result<(), wasmer::RuntimeError> = guest::call(
+            &mut store.lock().as_store_mut(),
+            instance,
+            "loop_forever",
+            (),
+        );
+        let error = result.unwrap_err().downcast::<WasmError>().unwrap().error;
+        assert_eq!(WasmErrorInner::OutOfPoints, error);
+        assert!(error.maybe_corrupt());
+    }
+
+    #[test]
+    #[cfg(feature = "wasmer-sys")]
+    fn call_with_deadline_ok() {
+        let InstanceWithStore { store, instance } = TestWasm::Core.instance();
+        let result: Vec<u8> = guest::call_with_deadline(
+            store,
+            instance,
+            "ping",
+            (),
+            std::time::Instant::now() + std::time::Duration::from_secs(60),
+        )
+        .unwrap();
+        assert_eq!(vec![1], result);
+    }
+
+    #[test]
+    #[cfg(feature = "wasmer-sys")]
+    fn call_with_deadline_loop_forever() {
+        let InstanceWithStore { store, instance } = TestWasm::Core.instance();
+        let start = std::time::Instant::now();
+        let result: Result<(), wasmer::RuntimeError> = guest::call_with_deadline(
+            store.clone(),
+            instance,
+            "loop_forever",
+            (),
+            start + std::time::Duration::from_millis(100),
+        );
+        // Far less time than it takes to use up all the points.
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        let error = result.unwrap_err().downcast::<WasmError>().unwrap().error;
+        assert_eq!(WasmErrorInner::Timeout, error);
+        assert!(error.maybe_corrupt());
+        // The guest was interrupted so its thread has released the store.
+        assert!(store.try_lock().is_some());
+    }
+
+    #[test]
+    #[cfg(all(feature = "wasmer-wasmi", not(feature = "wasmer-sys")))]
+    fn call_with_deadline_wasmi() {
+        let InstanceWithStore { store, instance } = TestWasm::Core.instance();
+        let start = std::time::Instant::now();
+        let result: Result<(), wasmer::RuntimeError> = guest::call_with_deadline(
+            store.clone(),
+            instance,
+            "loop_forever",
+            (),
+            start + std::time::Duration::from_millis(100),
+        );
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        let error = result.unwrap_err().downcast::<WasmError>().unwrap().error;
+        assert_eq!(WasmErrorInner::Timeout, error);
+        assert!(store.try_lock().is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "wasmer-sys")]
+    fn memory_limit() {
+        use holochain_wasmer_host::module::sys;
+        use holochain_wasmer_host::module::InstanceBuilder;
+
+        let module = TestWasm::Core.module(false);
+        let initial_pages = module.exports().memories().next().unwrap().ty().minimum.0;
+        let InstanceWithStore { store, instance } = InstanceBuilder::new(module, || {
+            // A couple of pages to spare for small calls.
+            sys::make_runtime_engine_with_memory_limit(20)
+        })
+        .build(crate::import::imports)
+        .unwrap();
+        assert!(initial_pages <= 20);
+
+        let _: StringType = guest::call(
+            &mut store.lock().as_store_mut(),
+            instance.clone(),
+            "process_string",
+            StringType::from("foo".to_string()),
+        )
+        .unwrap();
+
+        let result: Result<StringType, _> = guest::call(
+            &mut store.lock().as_store_mut(),
+            instance,
+            "process_string",
+            StringType::from(".".repeat(10_000_000)),
+        );
+        let error = result.unwrap_err().downcast::<WasmError>().unwrap().error;
+        assert_eq!(WasmErrorInner::MemoryLimit, error);
+        assert!(error.maybe_corrupt());
+    }
+
+    #[test]
+    #[cfg(feature = "wasmer-sys")]
+    fn trap_errors() {
+        use holochain_wasmer_host::module::sys;
+        use holochain_wasmer_host::module::InstanceBuilder;
+        use holochain_wasmer_host::module::ModuleCache;
+
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (memory (export "memory") 1)
+                (func (export "__hc__allocate_1") (param i32) (result i32) i32.const 0)
+                (func (export "__hc__deallocate_1") (param i32 i32))
+                (func (export "unreachable") (param i32 i32) (result i64)
+                    unreachable)
+                (func $overflow (export "overflow") (param i32 i32) (result i64)
+                    local.get 0
+                    local.get 1
+                    call $overflow)
+                (func (export "out_of_bounds") (param i32 i32) (result i64)
+                    i32.const -1
+                    i64.load))"#,
+        )
+        .unwrap();
+        // The cache owns the engine that the module's code lives in, so it
+        // has to outlive the instance.
+        let module_cache = ModuleCache::new(sys::make_engine, sys::make_runtime_engine, None);
+        let module = module_cache.get([0u8; 32], &wasm).unwrap();
+        let InstanceWithStore { store, instance } =
+            InstanceBuilder::new(module, sys::make_runtime_engine)
+                .build(|_, _| wasmer::Imports::new())
+                .unwrap();
+
+        for (f, expected) in [
+            ("unreachable", WasmErrorInner::Unreachable),
+            ("overflow", WasmErrorInner::StackOverflow),
+            ("out_of_bounds", WasmErrorInner::OutOfBoundsMemory),
+        ] {
+            let result: Result<(), wasmer::RuntimeError> =
+                guest::call(&mut store.lock().as_store_mut(), instance.clone(), f, ());
+            let error = result.unwrap_err().downcast::<WasmError>().unwrap().error;
+            assert_eq!(expected, error);
+            assert!(error.maybe_corrupt());
+        }
+    }
+
     // Disabled when the test harness is running through the wasmi backend
     // (i.e. wasmi-only, no sys): wasmer 7.1.0's wasmi backend constructs a
     // wasm trap from a non-NUL-terminated byte vector in
@@ -306,6 +472,29 @@ pub mod tests {
         assert_eq!(&String::from(result), &expected_string,);
     }
 
+    #[test]
+    #[cfg(all(feature = "async", feature = "wasmer-sys"))]
+    fn call_async_test() {
+        use holochain_wasmer_host::module::InstanceBuilder;
+        use holochain_wasmer_host::module::InstanceWithStoreAsync;
+
+        let InstanceWithStoreAsync { store, instance } = InstanceBuilder::new(
+            TestWasm::Core.module(false),
+            holochain_wasmer_host::module::sys::make_runtime_engine,
+        )
+        .build_async(crate::import::imports_async)
+        .unwrap();
+
+        let result: StringType = futures::executor::block_on(guest::call_async(
+            &store,
+            instance,
+            "process_string",
+            StringType::from("foo".to_string()),
+        ))
+        .unwrap();
+        assert_eq!("host: guest: foo", String::from(result));
+    }
+
     #[test]
     fn concurrent_calls() {
         let some_inner = "foo";
@@ -466,7 +655,6 @@ pub mod tests {
     }
 
     #[test]
-    #[cfg_attr(not(feature = "wasmer-sys"), ignore)]
     fn decrease_points_test() {
         let InstanceWithStore { store, instance } = TestWasm::Core.instance();
         let dec_by = 1_000_000_u64;
@@ -504,6 +692,77 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn set_remaining_points_per_call() {
+        let input = StringType::from(".".repeat(10_000));
+
+        // Not enough points for the call to complete.
+        let InstanceWithStore { store, instance } = TestWasm::Core.instance();
+        guest::set_remaining_points(&mut store.lock().as_store_mut(), &instance, 1_000).unwrap();
+        let result: Result<StringType, _> = guest::call(
+            &mut store.lock().as_store_mut(),
+            instance,
+            "process_string",
+            input.clone(),
+        );
+        assert!(result.is_err());
+
+        // Plenty of points, and the call only draws from this budget.
+        let InstanceWithStore { store, instance } = TestWasm::Core.instance();
+        guest::set_remaining_points(&mut store.lock().as_store_mut(), &instance, 100_000_000)
+            .unwrap();
+        let _: StringType = guest::call(
+            &mut store.lock().as_store_mut(),
+            instance.clone(),
+            "process_string",
+            input,
+        )
+        .unwrap();
+        let points_after: u64 = instance
+            .exports
+            .get_global("wasmer_metering_remaining_points")
+            .unwrap()
+            .get(&mut store.lock().as_store_mut())
+            .unwrap_i64()
+            .try_into()
+            .unwrap();
+        assert!(points_after < 100_000_000);
+    }
+
+    #[test]
+    fn call_with_stats_test() {
+        let input = StringType::from("foo".to_string());
+        let InstanceWithStore { store, instance } = TestWasm::Core.instance();
+        let (result, stats) = guest::call_with_stats::<_, StringType>(
+            &mut store.lock().as_store_mut(),
+            instance,
+            "process_string",
+            input,
+        );
+
+        assert_eq!("host: guest: foo", String::from(result.unwrap()));
+        assert!(stats.bytes_in > "foo".len());
+        assert!(stats.bytes_out > "host: guest: foo".len());
+        assert!(!stats.points_exhausted);
+        assert!(stats.points_consumed.unwrap() > 0);
+    }
+
+    #[test]
+    fn call_with_stats_exhausted() {
+        let InstanceWithStore { store, instance } = TestWasm::Core.instance();
+        guest::set_remaining_points(&mut store.lock().as_store_mut(), &instance, 1_000).unwrap();
+        let (result, stats) = guest::call_with_stats::<_, StringType>(
+            &mut store.lock().as_store_mut(),
+            instance,
+            "process_string",
+            StringType::from(".".repeat(10_000)),
+        );
+
+        assert!(result.is_err());
+        assert!(stats.points_exhausted);
+        assert_eq!(Some(1_000), stats.points_consumed);
+    }
+
     #[test]
     fn nested_call_test() {
         // Call a guest fn
@@ -1,11 +1,12 @@
 use crate::import::imports;
 #[cfg(all(feature = "wasmer-wasmi", not(feature = "wasmer-sys")))]
-use holochain_wasmer_host::module::wasmi::build_module;
+use holochain_wasmer_host::module::wasmi;
+#[cfg(all(feature = "wasmer-wasmi", not(feature = "wasmer-sys")))]
+use holochain_wasmer_host::module::CostModel;
+use holochain_wasmer_host::module::InstanceBuilder;
 use holochain_wasmer_host::module::InstanceWithStore;
-#[cfg(feature = "wasmer-sys")]
 use holochain_wasmer_host::module::ModuleBuilder;
 use holochain_wasmer_host::module::ModuleCache;
-use holochain_wasmer_host::prelude::*;
 use once_cell::sync::OnceCell;
 use parking_lot::RwLock;
 use std::sync::Arc;
@@ -13,14 +14,9 @@ use std::sync::Arc;
 use wasmer::sys::CompilerConfig;
 #[cfg(feature = "wasmer-sys")]
 use wasmer::wasmparser::Operator;
-use wasmer::AsStoreMut;
 #[cfg(feature = "wasmer-sys")]
 use wasmer::Engine;
-use wasmer::FunctionEnv;
-use wasmer::Imports;
-use wasmer::Instance;
 use wasmer::Module;
-use wasmer::Store;
 #[cfg(feature = "wasmer-sys")]
 use wasmer_middlewares::Metering;
 
@@ -65,16 +61,14 @@ impl TestWasm {
         }
     }
 
-    pub fn key(&self, metered: bool) -> [u8; 32] {
-        match (self, metered) {
-            (TestWasm::Empty, false) => [0; 32],
-            (TestWasm::Empty, true) => [1; 32],
-            (TestWasm::Io, false) => [2; 32],
-            (TestWasm::Io, true) => [3; 32],
-            (TestWasm::Core, false) => [4; 32],
-            (TestWasm::Core, true) => [5; 32],
-            (TestWasm::Memory, false) => [6; 32],
-            (TestWasm::Memory, true) => [7; 32],
+    /// Metered and unmetered modules are cached apart, by separate caches in
+    /// memory and by builder fingerprint on disk, so the key is just the wasm.
+    pub fn key(&self) -> [u8; 32] {
+        match self {
+            TestWasm::Empty => [0; 32],
+            TestWasm::Io => [1; 32],
+            TestWasm::Core => [2; 32],
+            TestWasm::Memory => [3; 32],
         }
     }
 
@@ -94,7 +88,7 @@ impl TestWasm {
     #[cfg(feature = "wasmer-sys")]
     pub fn module(&self, metered: bool) -> Arc<Module> {
         match self.module_cache(metered).get() {
-            Some(cache) => cache.write().get(self.key(metered), self.bytes()).unwrap(),
+            Some(cache) => cache.write().get(self.key(), self.bytes()).unwrap(),
             None => {
                 let metered_fn = || {
                     let cost_function = |_operator: &Operator| -> u64 { 1 };
@@ -127,7 +121,8 @@ impl TestWasm {
                         ModuleBuilder::new(
                             if metered { metered_fn } else { unmetered_fn },
                             holochain_wasmer_host::module::sys::make_runtime_engine,
-                        ),
+                        )
+                        .interruptible(true),
                         None,
                     ),
                 ));
@@ -139,82 +134,41 @@ impl TestWasm {
     }
 
     #[cfg(all(feature = "wasmer-wasmi", not(feature = "wasmer-sys")))]
-    pub fn module(&self, _metered: bool) -> Arc<Module> {
-        build_module(self.bytes()).unwrap()
+    pub fn module(&self, metered: bool) -> Arc<Module> {
+        let builder = ModuleBuilder::new(wasmi::make_engine, wasmi::make_runtime_engine)
+            .interruptible(true);
+        let builder = if metered {
+            builder.instrument(|wasm| {
+                wasmi::meter_with_metering(wasm, 10_000_000_000, CostModel::flat())
+            })
+        } else {
+            builder
+        };
+        builder.from_binary(self.bytes()).unwrap()
     }
 
     pub fn _instance(&self, metered: bool) -> InstanceWithStore {
         let module = self.module(metered);
-        // The sys backend lets us pair any engine with any store, so a default
-        // store is fine. wasmi keeps a per-engine function-type registry and
-        // panics with "encountered foreign entity in func type registry" if
-        // the store and module disagree on engine, so the wasmi-only branch
-        // builds the store from the same shared engine that built the module.
+        // The sys backend lets us pair any engine with any store, but wasmi
+        // keeps a per-engine function-type registry and panics with
+        // "encountered foreign entity in func type registry" if the store and
+        // module disagree on engine, so the wasmi-only branch builds the store
+        // from the same shared engine that built the module.
         #[cfg(feature = "wasmer-sys")]
-        let mut store = Store::default();
+        let make_store_engine = holochain_wasmer_host::module::sys::make_runtime_engine;
         #[cfg(all(feature = "wasmer-wasmi", not(feature = "wasmer-sys")))]
-        let mut store = Store::new(holochain_wasmer_host::module::wasmi::make_engine());
-        let function_env;
-        let instance;
-        {
-            let mut store_mut = store.as_store_mut();
-            function_env = FunctionEnv::new(&mut store_mut, Env::default());
-            let built_imports: Imports = imports(&mut store_mut, &function_env);
-            instance = Instance::new(&mut store_mut, &module, &built_imports).unwrap();
-        }
+        let make_store_engine = holochain_wasmer_host::module::wasmi::make_runtime_engine;
 
-        {
-            let mut function_env_mut = function_env.into_mut(&mut store);
-            let (data_mut, store_mut) = function_env_mut.data_and_store_mut();
-            data_mut.memory = Some(instance.exports.get_memory("memory").unwrap().clone());
-            data_mut.deallocate = Some(
-                instance
-                    .exports
-                    .get_typed_function(&store_mut, "__hc__deallocate_1")
-                    .unwrap(),
-            );
-            data_mut.allocate = Some(
-                instance
-                    .exports
-                    .get_typed_function(&store_mut, "__hc__allocate_1")
-                    .unwrap(),
-            );
-
-            #[cfg(feature = "wasmer-sys")]
-            if metered {
-                data_mut.wasmer_metering_points_exhausted = Some(
-                    instance
-                        .exports
-                        .get_global("wasmer_metering_points_exhausted")
-                        .unwrap()
-                        .clone(),
-                );
-                data_mut.wasmer_metering_remaining_points = Some(
-                    instance
-                        .exports
-                        .get_global("wasmer_metering_remaining_points")
-                        .unwrap()
-                        .clone(),
-                );
-            }
-        }
-
-        InstanceWithStore {
-            store: Arc::new(Mutex::new(store)),
-            instance: Arc::new(instance),
-        }
+        InstanceBuilder::new(module, make_store_engine)
+            .metered(metered)
+            .build(imports)
+            .unwrap()
     }
 
-    #[cfg(feature = "wasmer-sys")]
     pub fn instance(&self) -> InstanceWithStore {
         self._instance(true)
     }
 
-    #[cfg(all(feature = "wasmer-wasmi", not(feature = "wasmer-sys")))]
-    pub fn instance(&self) -> InstanceWithStore {
-        self.unmetered_instance()
-    }
-
     pub fn unmetered_instance(&self) -> InstanceWithStore {
         self._instance(false)
     }